@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::index::{
+    AccessScope, FacetCount, IndexError, IndexingPass, SearchHit, SearchOptions, SearchResults,
+    SortDirection, SortField, SortOrder,
+};
+use crate::indexer_worker::{background_indexing_pass, start_indexing_pass, IndexingProgress};
+use crate::webserver::{get_state, AuthenticatedUser};
+use lazy_static::lazy_static;
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::Responder;
+use rocket::serde::json::{self, json, Json, Value};
+use rocket::tokio::task;
+use rocket::{delete, get, post, routes, Request, Route};
+use schemars::gen::SchemaSettings;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: usize = 25;
+const MAX_LIMIT: usize = 500;
+
+lazy_static! {
+    // cancellation flag of the latest streaming search of each client session.
+    static ref streaming_searches: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+/**
+ * Routes of the versioned web api, mounted at the root.
+ */
+pub fn routes() -> Vec<Route> {
+    return routes![
+        route_search_get,
+        route_search_post,
+        route_search_stream,
+        route_indexing_status,
+        route_indexing_start,
+        route_indexing_cancel,
+        route_openapi
+    ];
+}
+
+/**
+ * A search for documents. The same fields are accepted as query parameters on GET or as a json body on POST, POST
+ * is for queries too long to fit in a url.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SearchRequest {
+    /** Query in the tantivy query syntax. */
+    pub query: String,
+    /** Number of hits to skip. */
+    #[serde(default)]
+    pub offset: usize,
+    /** Maximum number of hits to return, between 1 and 500. */
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /** Field to sort the hits by, relevance when not given. */
+    pub sort: Option<SortField>,
+    /** Sort direction, descending (best match, newest or largest first) when not given. */
+    pub order: Option<SortDirection>,
+    /** Also match query words within this many edits (at most 2), ranked below exact matches. */
+    pub fuzzy: Option<u8>,
+}
+
+fn default_limit() -> usize {
+    return DEFAULT_LIMIT;
+}
+
+/**
+ * A page of hits for a search.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SearchResponse {
+    pub results: Vec<SearchHit>,
+    /** Number of documents matching the query, including those outside this page. */
+    pub total_hits: usize,
+    /** Counts of matching documents by directory directly within each indexed directory. */
+    pub directories: Vec<FacetCount>,
+    /** Counts of matching documents by lowercased file extension. */
+    pub file_types: Vec<FacetCount>,
+    /** Milliseconds spent executing the query in the index. */
+    pub latency_ms: f64,
+}
+
+impl SearchResponse {
+    fn new(results: SearchResults) -> SearchResponse {
+        return SearchResponse {
+            latency_ms: results.latency_micros as f64 / 1000.0,
+            results: results.hits,
+            total_hits: results.total_hits,
+            directories: results.directories,
+            file_types: results.file_types,
+        };
+    }
+}
+
+/**
+ * Machine readable reason a request failed.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /** The request is malformed: a required parameter is missing or a value has the wrong type. (400) */
+    InvalidRequest,
+    /** The query text can't be parsed. (422) */
+    InvalidQuery,
+    /** A well formed option is out of range or can't be combined with the others. (422) */
+    InvalidOption,
+    /** Credentials are missing or don't match a configured user or token. (401) */
+    Unauthorized,
+    /** The user may only search part of the index, so can't use an endpoint covering all of it. (403) */
+    Forbidden,
+    /** An indexing pass is already running. (409) */
+    IndexingInProgress,
+    /** No indexing pass is running. (409) */
+    NotIndexing,
+    /** The index failed to execute the request. (500) */
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(&self) -> Status {
+        return match self {
+            ErrorCode::InvalidRequest => Status::BadRequest,
+            ErrorCode::InvalidQuery | ErrorCode::InvalidOption => Status::UnprocessableEntity,
+            ErrorCode::Unauthorized => Status::Unauthorized,
+            ErrorCode::Forbidden => Status::Forbidden,
+            ErrorCode::IndexingInProgress | ErrorCode::NotIndexing => Status::Conflict,
+            ErrorCode::InternalError => Status::InternalServerError,
+        };
+    }
+}
+
+/**
+ * Body of every failed api request, sent with the http status matching its code.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    /** Human readable description of the error. */
+    pub message: String,
+}
+
+impl ApiError {
+    pub(crate) fn new(code: ErrorCode, message: String) -> ApiError {
+        return ApiError {
+            code: code,
+            message: message,
+        };
+    }
+}
+
+impl From<IndexError> for ApiError {
+    fn from(e: IndexError) -> ApiError {
+        let code = match e {
+            IndexError::InvalidQuery(_) => ErrorCode::InvalidQuery,
+            IndexError::InvalidOption(_) => ErrorCode::InvalidOption,
+            _ => ErrorCode::InternalError,
+        };
+        return ApiError::new(code, e.to_string());
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        return (self.code.status(), Json(self)).respond_to(request);
+    }
+}
+
+#[get("/api/v1/search?<query>&<offset>&<limit>&<sort>&<order>&<fuzzy>")]
+fn route_search_get(
+    query: Option<String>,
+    offset: Option<String>,
+    limit: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    fuzzy: Option<String>,
+    user: AuthenticatedUser,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let request = search_request(query, offset, limit, sort, order, fuzzy)?;
+    return search(request, user);
+}
+
+// the body is parsed as json whatever its content type, so that any body that isn't gets an ApiError.
+#[post("/api/v1/search", data = "<request>")]
+fn route_search_post(
+    request: Result<Json<SearchRequest>, json::Error<'_>>,
+    user: AuthenticatedUser,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let request = match request {
+        Ok(request) => request.into_inner(),
+        Err(json::Error::Parse(_, e)) => {
+            return Err(ApiError::new(ErrorCode::InvalidRequest, e.to_string()))
+        }
+        Err(json::Error::Io(e)) => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("failed to read request body: {}", e),
+            ))
+        }
+    };
+    return search(request, user);
+}
+
+/**
+ * Streams the hits for a search as server-sent events: a `filenames` event with the cheap filename matches followed by
+ * a `results` event with the full-text matches, each holding a SearchResponse. A search started with the same
+ * `session` as a later search is cancelled, ending with a `cancelled` event instead, so a client searching as the user
+ * types only pays for its latest query. Failures end the stream with an `error` event holding an ApiError.
+ */
+#[get("/api/v1/search/stream?<query>&<offset>&<limit>&<sort>&<order>&<fuzzy>&<session>")]
+fn route_search_stream(
+    query: Option<String>,
+    offset: Option<String>,
+    limit: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    fuzzy: Option<String>,
+    session: Option<String>,
+    user: AuthenticatedUser,
+) -> Result<EventStream![], ApiError> {
+    let request = search_request(query, offset, limit, sort, order, fuzzy)?;
+    let options = search_options(&request, user.scope)?;
+    // sessions are per user so one user can't cancel another's searches.
+    let search = StreamingSearch::begin(
+        session.map(|session| format!("{}:{}", user.name.unwrap_or_default(), session)),
+    );
+    let db = get_state().db;
+
+    return Ok(EventStream! {
+        for (event, filenames_only) in [("filenames", true), ("results", false)] {
+            if search.is_cancelled() {
+                yield Event::empty().event("cancelled");
+                break;
+            }
+
+            let mut options = options.clone();
+            options.filenames_only = filenames_only;
+            options.cancelled = Some(search.cancelled.clone());
+            let db = db.clone();
+            let request = request.clone();
+            // searches are cpu bound, keep them off the async workers.
+            let results = task::spawn_blocking(move || {
+                db.search(&request.query, request.limit, request.offset, &options)
+            })
+            .await;
+
+            match results {
+                Ok(Ok(results)) => {
+                    if search.is_cancelled() {
+                        yield Event::empty().event("cancelled");
+                        break;
+                    }
+                    yield Event::json(&SearchResponse::new(results)).event(event);
+                }
+                Ok(Err(IndexError::Cancelled)) => {
+                    yield Event::empty().event("cancelled");
+                    break;
+                }
+                Ok(Err(e)) => {
+                    yield Event::json(&ApiError::from(e)).event("error");
+                    break;
+                }
+                Err(e) => {
+                    yield Event::json(&ApiError::new(ErrorCode::InternalError, e.to_string()))
+                        .event("error");
+                    break;
+                }
+            }
+        }
+        drop(search);
+    });
+}
+
+/**
+ * A streaming search registered under its client session, it is deregistered when dropped.
+ */
+struct StreamingSearch {
+    session: Option<String>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl StreamingSearch {
+    /**
+     * Registers a search, cancelling the previous search of the same session if it is still running.
+     */
+    fn begin(session: Option<String>) -> StreamingSearch {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        if let Some(session) = &session {
+            let mut searches = streaming_searches.lock().unwrap();
+            if let Some(previous) = searches.insert(session.clone(), cancelled.clone()) {
+                previous.store(true, Ordering::SeqCst);
+            }
+        }
+        return StreamingSearch {
+            session: session,
+            cancelled: cancelled,
+        };
+    }
+
+    fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+}
+
+impl Drop for StreamingSearch {
+    fn drop(&mut self) {
+        if let Some(session) = &self.session {
+            let mut searches = streaming_searches.lock().unwrap();
+            // a newer search of the session may already have taken its place.
+            if let Some(current) = searches.get(session) {
+                if Arc::ptr_eq(current, &self.cancelled) {
+                    searches.remove(session);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Builds a search request from query parameters.
+ */
+fn search_request(
+    query: Option<String>,
+    offset: Option<String>,
+    limit: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    fuzzy: Option<String>,
+) -> Result<SearchRequest, ApiError> {
+    let query = match query {
+        Some(query) => query,
+        None => {
+            return Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                "query parameter is required".to_string(),
+            ))
+        }
+    };
+
+    return Ok(SearchRequest {
+        query: query,
+        offset: parse_param("offset", offset)?.unwrap_or(0),
+        limit: parse_param("limit", limit)?.unwrap_or(DEFAULT_LIMIT),
+        sort: parse_param("sort", sort)?,
+        order: parse_param("order", order)?,
+        fuzzy: parse_param("fuzzy", fuzzy)?,
+    });
+}
+
+/**
+ * Parses an optional query parameter, a value that doesn't parse is rejected rather than treated as missing.
+ */
+pub(crate) fn parse_param<T>(name: &str, value: Option<String>) -> Result<Option<T>, ApiError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    return match value {
+        Some(value) => match value.parse::<T>() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) => Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("invalid {} parameter {:?}: {}", name, value, e),
+            )),
+        },
+        None => Ok(None),
+    };
+}
+
+fn search(
+    request: SearchRequest,
+    user: AuthenticatedUser,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let options = search_options(&request, user.scope)?;
+    let s = get_state();
+    let results =
+        s.db.search(&request.query, request.limit, request.offset, &options)?;
+    println!(
+        "query {:?} matched {} documents in {:.3}ms",
+        &request.query,
+        results.total_hits,
+        results.latency_micros as f64 / 1000.0
+    );
+    return Ok(Json(SearchResponse::new(results)));
+}
+
+/**
+ * Validates a request and converts it to the options for searching within the given scope.
+ */
+fn search_options(request: &SearchRequest, scope: AccessScope) -> Result<SearchOptions, ApiError> {
+    // the index rejects a zero limit itself.
+    if request.limit > MAX_LIMIT {
+        return Err(ApiError::new(
+            ErrorCode::InvalidOption,
+            format!("limit must be <= {}, found: {}", MAX_LIMIT, request.limit),
+        ));
+    }
+
+    let mut options = SearchOptions::default();
+    options.scope = scope;
+    options.facet_roots = get_state().config.indexed_paths();
+    if let Some(field) = request.sort {
+        options.sort = SortOrder {
+            field: field,
+            // best match / newest / largest first unless an order is given.
+            direction: SortDirection::Descending,
+        };
+    }
+    if let Some(direction) = request.order {
+        options.sort.direction = direction;
+    }
+    options.fuzzy_distance = request.fuzzy;
+    return Ok(options);
+}
+
+/**
+ * Indexing passes run by the server along with the last pass committed to the index.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct IndexingStatus {
+    /** The latest pass started by this server, running or not. None if it hasn't started one. */
+    pub current: Option<IndexingProgress>,
+    /** The last complete pass committed to the index, including passes run outside the server. */
+    pub last_pass: Option<IndexingPass>,
+}
+
+#[get("/api/v1/indexing")]
+fn route_indexing_status(user: AuthenticatedUser) -> Result<Json<IndexingStatus>, ApiError> {
+    require_unrestricted(&user)?;
+    return Ok(Json(IndexingStatus {
+        current: background_indexing_pass().map(|monitor| monitor.progress()),
+        last_pass: get_state().db.last_indexing_pass()?,
+    }));
+}
+
+/**
+ * Starts an indexing pass over the configured directories in the background, searches continue against the index
+ * while it runs.
+ */
+#[post("/api/v1/indexing")]
+fn route_indexing_start(
+    user: AuthenticatedUser,
+) -> Result<(Status, Json<IndexingProgress>), ApiError> {
+    require_unrestricted(&user)?;
+    let s = get_state();
+    return match start_indexing_pass(s.config.indexed_paths(), s.db.clone()) {
+        Some(monitor) => {
+            println!(
+                "indexing pass started by {}",
+                user.name.unwrap_or("anonymous".to_string())
+            );
+            Ok((Status::Accepted, Json(monitor.progress())))
+        }
+        None => Err(ApiError::new(
+            ErrorCode::IndexingInProgress,
+            "an indexing pass is already running".to_string(),
+        )),
+    };
+}
+
+/**
+ * Cancels the running indexing pass, it stops before its next file and keeps the documents indexed so far.
+ */
+#[delete("/api/v1/indexing")]
+fn route_indexing_cancel(user: AuthenticatedUser) -> Result<Json<IndexingProgress>, ApiError> {
+    require_unrestricted(&user)?;
+    return match background_indexing_pass() {
+        Some(monitor) if !monitor.is_finished() => {
+            monitor.cancel();
+            Ok(Json(monitor.progress()))
+        }
+        _ => Err(ApiError::new(
+            ErrorCode::NotIndexing,
+            "no indexing pass is running".to_string(),
+        )),
+    };
+}
+
+/**
+ * Endpoints covering the whole index would reveal what is outside a restricted user's scope.
+ */
+pub(crate) fn require_unrestricted(user: &AuthenticatedUser) -> Result<(), ApiError> {
+    if !user.scope.is_unrestricted() {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "only available to users that can search the whole index".to_string(),
+        ));
+    }
+    return Ok(());
+}
+
+#[get("/api/v1/openapi.json")]
+fn route_openapi(_user: AuthenticatedUser) -> Value {
+    return openapi_spec();
+}
+
+/**
+ * OpenAPI 3 description of the versioned api, the schemas are generated from the request and response types so the
+ * spec can't drift from what the server accepts.
+ */
+pub fn openapi_spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let request = gen.subschema_for::<SearchRequest>();
+    let response = gen.subschema_for::<SearchResponse>();
+    let error = gen.subschema_for::<ApiError>();
+    let indexing_status = gen.subschema_for::<IndexingStatus>();
+    let indexing_progress = gen.subschema_for::<IndexingProgress>();
+
+    // GET takes the fields of the request body as query parameters.
+    let mut parameters: Vec<Value> = Vec::new();
+    if let Some(Schema::Object(SchemaObject {
+        object: Some(object),
+        ..
+    })) = gen.definitions().get("SearchRequest")
+    {
+        for (name, schema) in &object.properties {
+            let description = match schema {
+                Schema::Object(schema) => schema
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.description.clone()),
+                _ => None,
+            };
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": object.required.contains(name),
+                "description": description,
+                "schema": schema,
+            }));
+        }
+    }
+
+    let responses = json!({
+        "200": {
+            "description": "A page of search hits.",
+            "content": { "application/json": { "schema": response } },
+        },
+        "400": {
+            "description": "The request is malformed, code `invalid_request`.",
+            "content": { "application/json": { "schema": error } },
+        },
+        "401": {
+            "description": "Authentication is enabled and the request carries no valid credentials.",
+        },
+        "422": {
+            "description": "The query can't be parsed or an option is out of range, code `invalid_query` or `invalid_option`.",
+            "content": { "application/json": { "schema": error } },
+        },
+        "500": {
+            "description": "The index failed to execute the search, code `internal_error`.",
+            "content": { "application/json": { "schema": error } },
+        },
+    });
+
+    let mut stream_parameters = parameters.clone();
+    stream_parameters.push(json!({
+        "name": "session",
+        "in": "query",
+        "required": false,
+        "description": "Client chosen id, starting a search cancels the previous search with the same session.",
+        "schema": { "type": "string" },
+    }));
+    let mut stream_responses = responses.clone();
+    stream_responses["200"] = json!({
+        "description": "Server-sent events: `filenames` then `results` with a SearchResponse each, or `cancelled` once \
+            a newer search of the session starts, or `error` with an ApiError.",
+        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+    });
+
+    let error_response = |description: &str| {
+        return json!({
+            "description": description,
+            "content": { "application/json": { "schema": error } },
+        });
+    };
+    let forbidden = error_response("The user can only search part of the index, code `forbidden`.");
+
+    return json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Guidebook",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/api/v1/search": {
+                "get": {
+                    "operationId": "search",
+                    "summary": "Search the indexed documents.",
+                    "parameters": parameters,
+                    "responses": responses,
+                },
+                "post": {
+                    "operationId": "searchPost",
+                    "summary": "Search the indexed documents, for queries too long for a url.",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": request } },
+                    },
+                    "responses": responses,
+                },
+            },
+            "/api/v1/search/stream": {
+                "get": {
+                    "operationId": "searchStream",
+                    "summary": "Stream filename matches and then full-text matches for a search as it is typed.",
+                    "parameters": stream_parameters,
+                    "responses": stream_responses,
+                },
+            },
+            "/api/v1/indexing": {
+                "get": {
+                    "operationId": "indexingStatus",
+                    "summary": "Progress of the latest indexing pass started by the server and the last complete pass.",
+                    "responses": {
+                        "200": {
+                            "description": "Indexing status.",
+                            "content": { "application/json": { "schema": indexing_status } },
+                        },
+                        "403": forbidden,
+                    },
+                },
+                "post": {
+                    "operationId": "startIndexing",
+                    "summary": "Start an indexing pass over the configured directories in the background.",
+                    "responses": {
+                        "202": {
+                            "description": "The pass started.",
+                            "content": { "application/json": { "schema": indexing_progress } },
+                        },
+                        "403": forbidden,
+                        "409": error_response("A pass is already running, code `indexing_in_progress`."),
+                    },
+                },
+                "delete": {
+                    "operationId": "cancelIndexing",
+                    "summary": "Cancel the running indexing pass, keeping the documents indexed so far.",
+                    "responses": {
+                        "200": {
+                            "description": "The pass is stopping.",
+                            "content": { "application/json": { "schema": indexing_progress } },
+                        },
+                        "403": forbidden,
+                        "409": error_response("No pass is running, code `not_indexing`."),
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "basic": { "type": "http", "scheme": "basic" },
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "security": [{ "basic": [] }, { "bearer": [] }],
+    });
+}
@@ -1,7 +1,12 @@
+use crate::index::metadata_store::MetadataBackend;
+use crate::index::tantivy_backend::MergePolicySettings;
+use crate::index::{AccessScope, UnixUser};
 use anyhow::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 
 /**
  * Guidebook's configuration
@@ -10,11 +15,191 @@ use std::path::Path;
 pub struct Config {
     pub database_location: String,
     pub indexed_directories: Vec<IndexedDirectory>,
+    // keep a compressed copy of the text extracted from each file so the index can be rebuilt without re-reading them.
+    #[serde(default)]
+    pub store_content: bool,
+    // format of the keyvalue store holding document metadata, either lmdb, sqlite or leveldb.
+    #[serde(default)]
+    pub metadata_store: MetadataBackend,
+    // how segments of the tantivy index are merged as documents are added.
+    #[serde(default)]
+    pub merge_policy: MergePolicySettings,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    // run indexing passes automatically while the web server or daemon is up, for directories without a schedule of
+    // their own.
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+}
+
+/**
+ * When to run indexing passes automatically, either every `interval` (e.g. `6h` or `30m`) or at the times matching a
+ * `cron` expression. Each pass starts a random delay of up to `jitter` after its scheduled time.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    pub interval: Option<String>,
+    pub cron: Option<String>, // five fields, or six with a leading seconds field, evaluated in local time.
+    pub jitter: Option<String>,
+}
+
+/**
+ * Where the web ui listens, it listens on every interface over plain http unless configured otherwise.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebConfig {
+    #[serde(default = "WebConfig::default_address")]
+    pub address: IpAddr,
+    #[serde(default = "WebConfig::default_port")]
+    pub port: u16,
+    // serve https with the given PEM encoded certificate chain and private key.
+    pub tls: Option<TlsConfig>,
+}
+
+impl WebConfig {
+    fn default_address() -> IpAddr {
+        return IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    }
+
+    fn default_port() -> u16 {
+        return 8080;
+    }
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        return WebConfig {
+            address: WebConfig::default_address(),
+            port: WebConfig::default_port(),
+            tls: None,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub certs: String,
+    pub key: String,
+}
+
+/**
+ * Credentials accepted by the web ui and api. Once any are configured every request must present one of them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: Vec<UserConfig>, // accepted with http basic auth.
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>, // accepted as bearer tokens, intended for scripted clients.
+    // paths that users and groups may search, everyone can search everything when there are no rules.
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
+    // hide files that the user's uid and gids couldn't read according to the permissions recorded at indexing time.
+    #[serde(default)]
+    pub honor_unix_permissions: bool,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        return !self.users.is_empty() || !self.tokens.is_empty();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub username: String,
+    // argon2 hash of the user's password in PHC string format, as printed by the hash-password subcommand.
+    pub password_hash: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    // unix identity checked against file permissions when honor_unix_permissions is set.
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TokenConfig {
+    pub name: String, // identifies the client holding the token.
+    pub token: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+}
+
+// the config is logged on startup, keep tokens out of the logs.
+impl fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f
+            .debug_struct("TokenConfig")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("groups", &self.groups)
+            .field("uid", &self.uid)
+            .field("gids", &self.gids)
+            .finish();
+    }
+}
+
+/**
+ * Grants the listed users and members of the listed groups access to everything under the given paths, which can be
+ * indexed directories or any directory within them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+impl AuthConfig {
+    /**
+     * The documents a user or token with the given name, groups and unix identity may search.
+     */
+    pub fn scope(&self, name: &str, groups: &Vec<String>, unix_user: UnixUser) -> AccessScope {
+        let paths = if self.access.is_empty() {
+            None
+        } else {
+            let granted: Vec<PathBuf> = self
+                .access
+                .iter()
+                .filter(|rule| {
+                    rule.users.iter().any(|user| user == name)
+                        || rule.groups.iter().any(|group| groups.contains(group))
+                })
+                .flat_map(|rule| rule.paths.iter().map(PathBuf::from))
+                .collect();
+            // granting the root grants the whole index.
+            if granted.iter().any(|path| path == Path::new("/")) {
+                None
+            } else {
+                Some(granted)
+            }
+        };
+
+        return AccessScope {
+            paths: paths,
+            unix_user: if self.honor_unix_permissions {
+                Some(unix_user)
+            } else {
+                None
+            },
+        };
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IndexedDirectory {
     pub path: String,
+    // overrides the top level schedule for this directory.
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
 }
 
 impl Config {
@@ -31,4 +216,90 @@ impl Config {
         let config: Config = serde_yaml::from_str(&fs::read_to_string(&path)?)?;
         return Ok(config);
     }
+
+    /**
+     * Paths of the configured indexed directories.
+     */
+    pub fn indexed_paths(&self) -> Vec<PathBuf> {
+        return self
+            .indexed_directories
+            .iter()
+            .map(|indexed_dir| PathBuf::from(&indexed_dir.path))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_config(yaml: &str) -> AuthConfig {
+        return serde_yaml::from_str(yaml).unwrap();
+    }
+
+    fn unix_user(uid: u32) -> UnixUser {
+        return UnixUser {
+            uid: Some(uid),
+            gids: vec![100],
+        };
+    }
+
+    #[test]
+    fn scope_grants_every_path_without_rules() {
+        let scope = AuthConfig::default().scope("alice", &Vec::new(), unix_user(1000));
+        assert!(scope.paths.is_none());
+        assert!(scope.unix_user.is_none());
+    }
+
+    #[test]
+    fn scope_collects_the_paths_granted_to_the_user_and_their_groups() {
+        let auth = auth_config(
+            "
+            access:
+              - users: [alice]
+                paths: [/data/alice]
+              - groups: [staff, admins]
+                paths: [/data/shared, /data/staff]
+              - users: [bob]
+                paths: [/data/bob]
+            ",
+        );
+        let staff = vec![String::from("staff")];
+        assert_eq!(
+            auth.scope("alice", &staff, unix_user(1000)).paths,
+            Some(vec![
+                PathBuf::from("/data/alice"),
+                PathBuf::from("/data/shared"),
+                PathBuf::from("/data/staff"),
+            ])
+        );
+        assert_eq!(
+            auth.scope("alice", &Vec::new(), unix_user(1000)).paths,
+            Some(vec![PathBuf::from("/data/alice")])
+        );
+        // a user no rule applies to sees nothing rather than everything.
+        assert_eq!(
+            auth.scope("carol", &Vec::new(), unix_user(1000)).paths,
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn scope_granting_the_root_covers_the_whole_index() {
+        let auth = auth_config(
+            "
+            honor_unix_permissions: true
+            access:
+              - groups: [admins]
+                paths: [/]
+              - users: [alice]
+                paths: [/data/alice]
+            ",
+        );
+        let scope = auth.scope("alice", &vec![String::from("admins")], unix_user(1000));
+        assert!(scope.paths.is_none());
+        let unix_user = scope.unix_user.unwrap();
+        assert_eq!(unix_user.uid, Some(1000));
+        assert_eq!(unix_user.gids, vec![100]);
+    }
 }
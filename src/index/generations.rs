@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// file under the database location naming the generation directory that is currently live.
+const CURRENT_POINTER: &str = "current";
+const GENERATION_PREFIX: &str = "generation-";
+
+/**
+ * Resolves the directory holding the live index and keyvalue store. Databases that have never been rebuilt keep them
+ * directly under the database location.
+ */
+pub fn current_dir(database_location: &Path) -> Result<PathBuf> {
+    let pointer = database_location.join(CURRENT_POINTER);
+    if !pointer.exists() {
+        return Ok(PathBuf::from(database_location));
+    }
+
+    let name = fs::read_to_string(&pointer)
+        .context(format!("failed to read {}", pointer.to_string_lossy()))?;
+    return Ok(database_location.join(name.trim()));
+}
+
+/**
+ * Creates an empty directory for a new generation of the index beside the live one.
+ */
+pub fn create_generation(database_location: &Path) -> Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let dir = database_location.join(format!("{}{}", GENERATION_PREFIX, timestamp));
+    fs::create_dir(&dir).context(format!(
+        "failed to create generation directory {}",
+        dir.to_string_lossy()
+    ))?;
+    return Ok(dir);
+}
+
+/**
+ * Atomically points the database location at the given generation directory by renaming a new pointer file over the
+ * old one.
+ */
+pub fn set_current(database_location: &Path, generation_dir: &Path) -> Result<()> {
+    let name = generation_dir
+        .file_name()
+        .context("generation directory has no name")?
+        .to_string_lossy();
+
+    let pointer_tmp = database_location.join(format!("{}.tmp", CURRENT_POINTER));
+    {
+        let mut file = fs::File::create(&pointer_tmp)?;
+        file.write_all(name.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&pointer_tmp, database_location.join(CURRENT_POINTER))?;
+    return Ok(());
+}
+
+/**
+ * Deletes every generation other than the ones given, including the original index and keyvalue store kept directly
+ * under the database location.
+ */
+pub fn remove_stale(database_location: &Path, keep: &[&Path]) -> Result<()> {
+    let mut stale: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(database_location)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() && name.starts_with(GENERATION_PREFIX) {
+            stale.push(path);
+        }
+    }
+    if !keep.contains(&database_location) {
+        stale.push(database_location.join("index"));
+        stale.push(database_location.join("kvstore"));
+    }
+
+    for path in stale {
+        if !path.exists() || keep.contains(&path.as_path()) {
+            continue;
+        }
+        println!("removing stale index generation {}", path.to_string_lossy());
+        fs::remove_dir_all(&path)?;
+    }
+    return Ok(());
+}
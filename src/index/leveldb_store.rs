@@ -0,0 +1,154 @@
+use crate::index::metadata_store::*;
+use crate::index::IndexResult;
+use db_key::Key;
+use leveldb::batch::{Batch, Writebatch};
+use leveldb::database::Database;
+use leveldb::iterator::{Iterable, LevelDBIterator};
+use leveldb::kv::KV;
+use leveldb::options::{Options, ReadOptions, WriteOptions};
+use leveldb::snapshots::Snapshots;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/**
+ * Keys are prefixed with the name of their table so that every table lives in the one leveldb database and each table's
+ * entries sort next to each other.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TableKey(Vec<u8>);
+
+impl TableKey {
+    fn new(table: Table, key: &str) -> TableKey {
+        return TableKey(format!("{}:{}", table.name(), key).into_bytes());
+    }
+
+    /**
+     * Strips the table prefix, None if the key belongs to a different table.
+     */
+    fn key_in(&self, table: Table) -> Option<String> {
+        let prefix = format!("{}:", table.name());
+        if !self.0.starts_with(prefix.as_bytes()) {
+            return None;
+        }
+        return Some(String::from_utf8_lossy(&self.0[prefix.len()..]).to_string());
+    }
+}
+
+impl Key for TableKey {
+    fn from_u8(key: &[u8]) -> TableKey {
+        return TableKey(key.to_vec());
+    }
+
+    fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+        return f(&self.0);
+    }
+}
+
+/**
+ * Metadata store backed by a leveldb database.
+ */
+pub struct LeveldbMetadataStore {
+    database: Arc<Database<TableKey>>,
+}
+
+impl LeveldbMetadataStore {
+    pub fn open(dir: &Path) -> IndexResult<LeveldbMetadataStore> {
+        if !dir.exists() {
+            fs::create_dir(dir)?;
+        }
+
+        let mut options = Options::new();
+        options.create_if_missing = true;
+        return Ok(LeveldbMetadataStore {
+            database: Arc::new(Database::open(dir, options)?),
+        });
+    }
+}
+
+impl MetadataStore for LeveldbMetadataStore {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        return Ok(self
+            .database
+            .get(ReadOptions::new(), TableKey::new(table, key))?);
+    }
+
+    fn is_empty(&self, table: Table) -> IndexResult<bool> {
+        let start = TableKey::new(table, "");
+        let first = self.database.iter(ReadOptions::new()).from(&start).next();
+        return Ok(first.map_or(true, |(key, _)| key.key_in(table).is_none()));
+    }
+
+    fn for_each(
+        &self,
+        table: Table,
+        visit: &mut dyn FnMut(&str, &[u8]) -> IndexResult<()>,
+    ) -> IndexResult<()> {
+        let snapshot = self.database.snapshot();
+        let start = TableKey::new(table, "");
+        for (key, value) in snapshot.iter(ReadOptions::new()).from(&start) {
+            match key.key_in(table) {
+                Some(key) => visit(&key, &value)?,
+                None => break,
+            }
+        }
+        return Ok(());
+    }
+
+    fn begin_write(&self) -> IndexResult<Box<dyn MetadataWriteTransaction>> {
+        return Ok(Box::new(LeveldbWriteTransaction {
+            database: self.database.clone(),
+            changes: BTreeMap::new(),
+        }));
+    }
+
+    fn copy_to(&self, dir: &Path) -> IndexResult<()> {
+        let copy = LeveldbMetadataStore::open(dir)?;
+        return copy_metadata(self, &copy);
+    }
+}
+
+/**
+ * leveldb has no transactions, writes are buffered and applied as a single atomic write batch on commit.
+ */
+struct LeveldbWriteTransaction {
+    database: Arc<Database<TableKey>>,
+    changes: BTreeMap<TableKey, Option<Vec<u8>>>,
+}
+
+impl MetadataWriteTransaction for LeveldbWriteTransaction {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        let key = TableKey::new(table, key);
+        if let Some(change) = self.changes.get(&key) {
+            return Ok(change.clone());
+        }
+        return Ok(self.database.get(ReadOptions::new(), key)?);
+    }
+
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> IndexResult<()> {
+        self.changes
+            .insert(TableKey::new(table, key), Some(value.to_vec()));
+        return Ok(());
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> IndexResult<()> {
+        self.changes.insert(TableKey::new(table, key), None);
+        return Ok(());
+    }
+
+    fn commit(self: Box<Self>) -> IndexResult<()> {
+        let mut batch = Writebatch::new();
+        for (key, change) in self.changes {
+            match change {
+                Some(value) => batch.put(key, &value),
+                None => batch.delete(key),
+            }
+        }
+
+        let mut options = WriteOptions::new();
+        options.sync = true;
+        self.database.write(options, &batch)?;
+        return Ok(());
+    }
+}
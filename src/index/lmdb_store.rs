@@ -0,0 +1,162 @@
+use crate::index::metadata_store::*;
+use crate::index::IndexResult;
+use lmdb::LmdbResultExt;
+use lmdb_zero as lmdb;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+struct LmdbTables {
+    env: Arc<lmdb::Environment>,
+    indexed_files: lmdb::Database<'static>,
+    meta: lmdb::Database<'static>,
+    journal: lmdb::Database<'static>,
+    content: lmdb::Database<'static>,
+    unextracted: lmdb::Database<'static>,
+}
+
+impl LmdbTables {
+    fn database(&self, table: Table) -> &lmdb::Database<'static> {
+        return match table {
+            Table::IndexedFiles => &self.indexed_files,
+            Table::Meta => &self.meta,
+            Table::Journal => &self.journal,
+            Table::Content => &self.content,
+            Table::Unextracted => &self.unextracted,
+        };
+    }
+}
+
+/**
+ * Metadata store backed by an lmdb environment, each table is a named lmdb database.
+ */
+pub struct LmdbMetadataStore {
+    tables: Arc<LmdbTables>,
+}
+
+impl LmdbMetadataStore {
+    pub fn open(dir: &Path) -> IndexResult<LmdbMetadataStore> {
+        if !dir.exists() {
+            fs::create_dir(dir)?;
+        }
+
+        let env = Arc::new(unsafe {
+            let gb = 1024 * 1024 * 1024;
+            let mut builder = lmdb::EnvBuilder::new()?;
+            builder.set_maxdbs(8)?;
+            builder.set_mapsize(128 * gb)?;
+            // NOTLS ties reader slots to transactions rather than threads so reads can nest, e.g. while iterating a table.
+            builder.open(&dir.to_string_lossy(), lmdb::open::NOTLS, 0o600)?
+        });
+
+        let open_database = |table: Table| {
+            return lmdb::Database::open(
+                env.clone(),
+                Some(table.name()),
+                &lmdb::DatabaseOptions::create_map::<str>(),
+            );
+        };
+        let tables = LmdbTables {
+            indexed_files: open_database(Table::IndexedFiles)?,
+            meta: open_database(Table::Meta)?,
+            journal: open_database(Table::Journal)?,
+            content: open_database(Table::Content)?,
+            unextracted: open_database(Table::Unextracted)?,
+            env: env.clone(),
+        };
+        return Ok(LmdbMetadataStore {
+            tables: Arc::new(tables),
+        });
+    }
+}
+
+impl MetadataStore for LmdbMetadataStore {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        let txn = lmdb::ReadTransaction::new(self.tables.env.clone())?;
+        let access = txn.access();
+        let value: Option<&[u8]> = access.get(self.tables.database(table), key).to_opt()?;
+        return Ok(value.map(|value| value.to_vec()));
+    }
+
+    fn is_empty(&self, table: Table) -> IndexResult<bool> {
+        let txn = lmdb::ReadTransaction::new(self.tables.env.clone())?;
+        let access = txn.access();
+        let first = txn
+            .cursor(self.tables.database(table))?
+            .first::<[u8], [u8]>(&access)
+            .to_opt()?;
+        return Ok(first.is_none());
+    }
+
+    fn for_each(
+        &self,
+        table: Table,
+        visit: &mut dyn FnMut(&str, &[u8]) -> IndexResult<()>,
+    ) -> IndexResult<()> {
+        let txn = lmdb::ReadTransaction::new(self.tables.env.clone())?;
+        let access = txn.access();
+        let mut cursor = txn.cursor(self.tables.database(table))?;
+        let entries = lmdb::CursorIter::new(
+            lmdb::MaybeOwned::Borrowed(&mut cursor),
+            &access,
+            |cursor, access| cursor.first(access),
+            lmdb::Cursor::next::<[u8], [u8]>,
+        )?;
+        for entry in entries {
+            let (key, value) = entry?;
+            visit(&String::from_utf8_lossy(key), value)?;
+        }
+        return Ok(());
+    }
+
+    fn begin_write(&self) -> IndexResult<Box<dyn MetadataWriteTransaction>> {
+        return Ok(Box::new(LmdbWriteTransaction {
+            txn: lmdb::WriteTransaction::new(self.tables.env.clone())?,
+            tables: self.tables.clone(),
+        }));
+    }
+
+    fn copy_to(&self, dir: &Path) -> IndexResult<()> {
+        fs::create_dir_all(dir)?;
+        self.tables
+            .env
+            .copy(&dir.to_string_lossy(), lmdb::copy::COMPACT)?;
+        return Ok(());
+    }
+}
+
+struct LmdbWriteTransaction {
+    txn: lmdb::WriteTransaction<'static>,
+    tables: Arc<LmdbTables>,
+}
+
+impl MetadataWriteTransaction for LmdbWriteTransaction {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        let access = self.txn.access();
+        let value: Option<&[u8]> = access.get(self.tables.database(table), key).to_opt()?;
+        return Ok(value.map(|value| value.to_vec()));
+    }
+
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> IndexResult<()> {
+        self.txn.access().put(
+            self.tables.database(table),
+            key,
+            value,
+            lmdb::put::Flags::empty(),
+        )?;
+        return Ok(());
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> IndexResult<()> {
+        self.txn
+            .access()
+            .del_key(self.tables.database(table), key)
+            .to_opt()?;
+        return Ok(());
+    }
+
+    fn commit(self: Box<Self>) -> IndexResult<()> {
+        self.txn.commit()?;
+        return Ok(());
+    }
+}
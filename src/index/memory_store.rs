@@ -0,0 +1,102 @@
+use crate::index::metadata_store::*;
+use crate::index::{IndexError, IndexResult};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+type Entries = BTreeMap<(Table, String), Vec<u8>>;
+
+/**
+ * Metadata store held entirely in memory, nothing is persisted once it is dropped.
+ */
+pub struct MemoryMetadataStore {
+    entries: Arc<RwLock<Entries>>,
+}
+
+impl MemoryMetadataStore {
+    pub fn new() -> MemoryMetadataStore {
+        return MemoryMetadataStore {
+            entries: Arc::new(RwLock::new(BTreeMap::new())),
+        };
+    }
+}
+
+impl MetadataStore for MemoryMetadataStore {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        let entries = self.entries.read().unwrap();
+        return Ok(entries.get(&(table, key.to_string())).cloned());
+    }
+
+    fn is_empty(&self, table: Table) -> IndexResult<bool> {
+        let entries = self.entries.read().unwrap();
+        return Ok(!entries.keys().any(|(entry_table, _)| *entry_table == table));
+    }
+
+    fn for_each(
+        &self,
+        table: Table,
+        visit: &mut dyn FnMut(&str, &[u8]) -> IndexResult<()>,
+    ) -> IndexResult<()> {
+        let entries = self.entries.read().unwrap();
+        let table_entries = entries
+            .range((table, String::new())..)
+            .take_while(|((entry_table, _), _)| *entry_table == table);
+        for ((_, key), value) in table_entries {
+            visit(key, value)?;
+        }
+        return Ok(());
+    }
+
+    fn begin_write(&self) -> IndexResult<Box<dyn MetadataWriteTransaction>> {
+        return Ok(Box::new(MemoryWriteTransaction {
+            entries: self.entries.clone(),
+            changes: BTreeMap::new(),
+        }));
+    }
+
+    fn copy_to(&self, _dir: &Path) -> IndexResult<()> {
+        return Err(IndexError::Unsupported(
+            "copying an in-memory metadata store to disk",
+        ));
+    }
+}
+
+/**
+ * Buffers writes until commit, a removed key is recorded as None.
+ */
+struct MemoryWriteTransaction {
+    entries: Arc<RwLock<Entries>>,
+    changes: BTreeMap<(Table, String), Option<Vec<u8>>>,
+}
+
+impl MetadataWriteTransaction for MemoryWriteTransaction {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        let key = (table, key.to_string());
+        if let Some(change) = self.changes.get(&key) {
+            return Ok(change.clone());
+        }
+        return Ok(self.entries.read().unwrap().get(&key).cloned());
+    }
+
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> IndexResult<()> {
+        self.changes
+            .insert((table, key.to_string()), Some(value.to_vec()));
+        return Ok(());
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> IndexResult<()> {
+        self.changes.insert((table, key.to_string()), None);
+        return Ok(());
+    }
+
+    fn commit(self: Box<Self>) -> IndexResult<()> {
+        let mut entries = self.entries.write().unwrap();
+        for (key, change) in self.changes {
+            match change {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+        return Ok(());
+    }
+}
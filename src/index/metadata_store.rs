@@ -0,0 +1,245 @@
+#[cfg(feature = "leveldb")]
+use crate::index::leveldb_store::LeveldbMetadataStore;
+use crate::index::lmdb_store::LmdbMetadataStore;
+use crate::index::sqlite_store::SqliteMetadataStore;
+use crate::index::{IndexError, IndexResult};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+// entries copied per transaction when converting between stores, bounds the memory held by buffered writes.
+const COPY_BATCH_SIZE: usize = 10_000;
+
+/**
+ * On-disk formats available for the metadata store kept alongside a tantivy index.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataBackend {
+    Lmdb,
+    Sqlite,
+    Leveldb, // only available in builds with the leveldb feature.
+}
+
+impl Default for MetadataBackend {
+    fn default() -> Self {
+        return MetadataBackend::Lmdb;
+    }
+}
+
+impl FromStr for MetadataBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<MetadataBackend> {
+        return match s {
+            "lmdb" => Ok(MetadataBackend::Lmdb),
+            "sqlite" => Ok(MetadataBackend::Sqlite),
+            "leveldb" => Ok(MetadataBackend::Leveldb),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown metadata store {:?}, expected one of lmdb, sqlite or leveldb",
+                s
+            ))),
+        };
+    }
+}
+
+impl MetadataBackend {
+    /**
+     * Opens the metadata store in the given directory, creating it if it doesn't exist. Fails rather than creating a
+     * second, empty store if the directory already holds a store in another format.
+     */
+    pub fn open(&self, dir: &Path) -> IndexResult<Box<dyn MetadataStore>> {
+        if let Some(found) = MetadataBackend::detect(dir) {
+            if found != *self {
+                return Err(IndexError::MetadataBackendMismatch(found, *self));
+            }
+        }
+
+        return Ok(match self {
+            MetadataBackend::Lmdb => Box::new(LmdbMetadataStore::open(dir)?),
+            MetadataBackend::Sqlite => Box::new(SqliteMetadataStore::open(dir)?),
+            #[cfg(feature = "leveldb")]
+            MetadataBackend::Leveldb => Box::new(LeveldbMetadataStore::open(dir)?),
+            #[cfg(not(feature = "leveldb"))]
+            MetadataBackend::Leveldb => {
+                return Err(IndexError::Unsupported(
+                    "the leveldb metadata store in a build without the leveldb feature",
+                ))
+            }
+        });
+    }
+
+    /**
+     * Identifies the format of the metadata store in the given directory from the files each backend creates.
+     */
+    pub fn detect(dir: &Path) -> Option<MetadataBackend> {
+        if dir.join("data.mdb").exists() {
+            return Some(MetadataBackend::Lmdb);
+        } else if dir.join("metadata.sqlite").exists() {
+            return Some(MetadataBackend::Sqlite);
+        } else if dir.join("CURRENT").exists() {
+            return Some(MetadataBackend::Leveldb);
+        }
+        return None;
+    }
+}
+
+/**
+ * Tables kept by a metadata store, each maps string keys to opaque values.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Table {
+    IndexedFiles, // document metadata as json keyed by path.
+    Meta,         // index wide values such as the generation and schema version.
+    Journal,      // paths added by the most recent commit keyed by generation.
+    Content,      // compressed keywords extracted from each document keyed by path.
+    Unextracted,  // paths indexed without their keywords, re-extracted by the next indexing pass.
+}
+
+impl Table {
+    pub const ALL: [Table; 5] = [
+        Table::IndexedFiles,
+        Table::Meta,
+        Table::Journal,
+        Table::Content,
+        Table::Unextracted,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Table::IndexedFiles => "indexed_files",
+            Table::Meta => "meta",
+            Table::Journal => "journal",
+            Table::Content => "content",
+            Table::Unextracted => "unextracted",
+        };
+    }
+}
+
+/**
+ * Keyvalue store holding the document metadata that sits alongside the tantivy index. Implementations must be safe to
+ * share between threads since searches read from it concurrently.
+ */
+pub trait MetadataStore: Sync + Send {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>>;
+
+    fn is_empty(&self, table: Table) -> IndexResult<bool>;
+
+    /**
+     * Calls visit with every entry in the table in key order, stopping at the first error.
+     */
+    fn for_each(
+        &self,
+        table: Table,
+        visit: &mut dyn FnMut(&str, &[u8]) -> IndexResult<()>,
+    ) -> IndexResult<()>;
+
+    /**
+     * Starts a transaction, none of its writes are visible to readers until it is committed.
+     */
+    fn begin_write(&self) -> IndexResult<Box<dyn MetadataWriteTransaction>>;
+
+    /**
+     * Writes a consistent copy of the store into the given directory in the store's own on-disk format.
+     */
+    fn copy_to(&self, dir: &Path) -> IndexResult<()>;
+}
+
+pub trait MetadataWriteTransaction {
+    /**
+     * Reads a value including any uncommitted writes made by this transaction.
+     */
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>>;
+
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> IndexResult<()>;
+
+    fn delete(&mut self, table: Table, key: &str) -> IndexResult<()>;
+
+    fn commit(self: Box<Self>) -> IndexResult<()>;
+}
+
+/**
+ * Copies every entry of every table from one store into another, used to convert a store between formats.
+ */
+pub fn copy_metadata(from: &dyn MetadataStore, to: &dyn MetadataStore) -> IndexResult<()> {
+    for table in Table::ALL {
+        // each batch is committed before the next begins, stores allow only one write transaction at a time.
+        let mut txn: Option<Box<dyn MetadataWriteTransaction>> = None;
+        let mut pending = 0;
+        from.for_each(table, &mut |key, value| {
+            let mut batch = match txn.take() {
+                Some(batch) => batch,
+                None => to.begin_write()?,
+            };
+            batch.put(table, key, value)?;
+            pending += 1;
+            if pending == COPY_BATCH_SIZE {
+                batch.commit()?;
+                pending = 0;
+            } else {
+                txn = Some(batch);
+            }
+            return Ok(());
+        })?;
+        if let Some(batch) = txn {
+            batch.commit()?;
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::memory_store::MemoryMetadataStore;
+    use crate::index::testing::TempDir;
+
+    /**
+     * Checks every entry of the source made it into the copy, which spans several batches.
+     */
+    fn assert_copies_in_batches(to: &dyn MetadataStore) {
+        let from = MemoryMetadataStore::new();
+        let mut txn = from.begin_write().unwrap();
+        for i in 0..COPY_BATCH_SIZE + 1 {
+            txn.put(Table::IndexedFiles, &format!("/data/{:05}.txt", i), b"{}")
+                .unwrap();
+        }
+        txn.put(Table::Meta, "generation", b"3").unwrap();
+        txn.commit().unwrap();
+
+        copy_metadata(&from, to).unwrap();
+        let mut copied = 0;
+        to.for_each(Table::IndexedFiles, &mut |_, _| {
+            copied += 1;
+            return Ok(());
+        })
+        .unwrap();
+        assert_eq!(copied, COPY_BATCH_SIZE + 1);
+        assert_eq!(
+            to.get(
+                Table::IndexedFiles,
+                &format!("/data/{:05}.txt", COPY_BATCH_SIZE)
+            )
+            .unwrap(),
+            Some(b"{}".to_vec())
+        );
+        assert_eq!(
+            to.get(Table::Meta, "generation").unwrap(),
+            Some(b"3".to_vec())
+        );
+    }
+
+    #[test]
+    fn copies_more_entries_than_a_batch_into_every_backend() {
+        assert_copies_in_batches(&MemoryMetadataStore::new());
+
+        let mut backends = vec![MetadataBackend::Lmdb, MetadataBackend::Sqlite];
+        if cfg!(feature = "leveldb") {
+            backends.push(MetadataBackend::Leveldb);
+        }
+        for backend in backends {
+            let dir = TempDir::new(&format!("copy-metadata-{:?}", backend));
+            assert_copies_in_batches(backend.open(&dir.path).unwrap().as_ref());
+        }
+    }
+}
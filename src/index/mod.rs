@@ -1,15 +1,77 @@
+pub mod generations;
+#[cfg(feature = "leveldb")]
+pub mod leveldb_store;
+pub mod lmdb_store;
+pub mod memory_store;
+pub mod metadata_store;
+pub mod sqlite_store;
 pub mod tantivy_backend;
+#[cfg(test)]
+pub mod testing;
 
 use anyhow::Result;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use std::{
     fs,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    time::UNIX_EPOCH,
     vec,
 };
 
+/**
+ * Errors raised while searching or writing to an index.
+ */
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("invalid query: {0}")]
+    InvalidQuery(#[from] tantivy::query::QueryParserError),
+    #[error("invalid search option: {0}")]
+    InvalidOption(String),
+    #[error("invalid document path: {0}")]
+    InvalidPath(#[from] tantivy::schema::FacetParseError),
+    #[error("indexed document is missing its {0} field")]
+    MissingField(&'static str),
+    #[error("index writer used after commit")]
+    WriterClosed,
+    #[error("search cancelled")]
+    Cancelled,
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+    #[error("{0} documents were indexed without storing their content, rebuild without --from-store to re-read them")]
+    ContentNotStored(usize),
+    #[error("index schema version {0} is newer than the supported version {1}")]
+    UnsupportedSchemaVersion(u32, u32),
+    #[error("tantivy error: {0}")]
+    Tantivy(#[from] tantivy::TantivyError),
+    #[error("keyvalue store error: {0}")]
+    KeyValueStore(#[from] lmdb_zero::Error),
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "leveldb")]
+    #[error("leveldb error: {0}")]
+    Leveldb(#[from] leveldb::error::Error),
+    #[error("keyvalue store is {0:?} but the config selects {1:?}, convert it with convert-kvstore")]
+    MetadataBackendMismatch(metadata_store::MetadataBackend, metadata_store::MetadataBackend),
+    #[error("failed to serialize document metadata: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("failed to decompress stored document content: {0}")]
+    Decompression(#[from] lz4_flex::block::DecompressError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type IndexResult<T> = std::result::Result<T, IndexError>;
+
 pub trait WritableIndex {
-    fn begin_add_documents(&mut self) -> Result<Box<dyn IndexWriter + '_>>;
+    /**
+     * Opens a writer on the index. Only one writer can be open at a time, searches continue to see the last commit
+     * while it is open.
+     */
+    fn begin_add_documents(&self) -> IndexResult<Box<dyn IndexWriter + '_>>;
 }
 
 // SearchableIndex represents an open index that can be searched, implementation
@@ -20,18 +82,47 @@ pub trait SearchableIndex: Sync + Send {
         query: &str,
         result_limit: usize,
         result_offset: usize,
-    ) -> Result<Vec<Document>>;
+        options: &SearchOptions,
+    ) -> IndexResult<SearchResults>;
+
+    /**
+     * Looks up an indexed document by its path.
+     */
+    fn get_document(&self, path: &Path) -> IndexResult<Option<Document>>;
+
+    /**
+     * Summary of the last indexing pass committed to the index, None if it has never been indexed.
+     */
+    fn last_indexing_pass(&self) -> IndexResult<Option<IndexingPass>>;
+
+    /**
+     * Lists the indexed files and directories directly within a directory, directories first followed by files in the
+     * given order.
+     */
+    fn browse(
+        &self,
+        path: &Path,
+        limit: usize,
+        offset: usize,
+        sort: SortOrder,
+        scope: &AccessScope,
+    ) -> IndexResult<BrowseResults>;
+
+    /**
+     * Summarizes what is in the index, documents under each of the given roots are counted separately.
+     */
+    fn stats(&self, roots: &Vec<PathBuf>) -> IndexResult<IndexStats>;
 }
 
 pub trait Index: WritableIndex + SearchableIndex {
-    fn as_writable(&mut self) -> &mut dyn WritableIndex;
+    fn as_writable(&self) -> &dyn WritableIndex;
     fn as_searchable(&self) -> &dyn SearchableIndex;
 }
 impl<T> Index for T
 where
     T: WritableIndex + SearchableIndex,
 {
-    fn as_writable(&mut self) -> &mut dyn WritableIndex {
+    fn as_writable(&self) -> &dyn WritableIndex {
         self
     }
     fn as_searchable(&self) -> &dyn SearchableIndex {
@@ -40,27 +131,86 @@ where
 }
 
 pub trait IndexWriter {
-    fn should_add_document(&mut self, path: &Path) -> bool;
-    fn add_document(&mut self, doc: &Document, keywords: &Vec<String>) -> Result<()>;
-    fn commit(&mut self) -> Result<()>;
+    fn should_add_document(&mut self, path: &Path) -> IndexResult<bool>;
+    fn add_document(&mut self, doc: &Document, keywords: &Vec<String>) -> IndexResult<()>;
+    /**
+     * Records a summary of the indexing pass, it is written as part of the next commit.
+     */
+    fn record_indexing_pass(&mut self, pass: &IndexingPass) -> IndexResult<()>;
+    fn commit(&mut self) -> IndexResult<()>;
 }
 
 /**
  * Represents only the metadata for a given document.
  */
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
 pub struct DocumentMetadata {
     pub path: PathBuf,
     pub size: u64,
+    #[serde(default)]
+    pub modified: u64, // last modification time in seconds since the unix epoch.
+    #[serde(default)]
+    pub permissions: Option<UnixPermissions>, // None on other platforms or if indexed before they were recorded.
+}
+
+/**
+ * Unix ownership and permission bits of a document at the time it was indexed.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Copy)]
+pub struct UnixPermissions {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+impl UnixPermissions {
+    /**
+     * Terms identifying who can read the file: anyone, members of its group or its owner. A user can read the file if
+     * any of these match the terms for their own identity, which is slightly more permissive than the kernel's check for
+     * the rare file whose owner or group has fewer permissions than everyone else.
+     */
+    pub fn readers(&self) -> Vec<String> {
+        let mut readers: Vec<String> = Vec::new();
+        if self.mode & 0o004 != 0 {
+            readers.push(String::from("other"));
+        }
+        if self.mode & 0o040 != 0 {
+            readers.push(format!("gid:{}", self.gid));
+        }
+        if self.mode & 0o400 != 0 {
+            readers.push(format!("uid:{}", self.uid));
+        }
+        return readers;
+    }
 }
 
 impl DocumentMetadata {
     pub fn from_path(path: &Path) -> Result<DocumentMetadata> {
         let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::MetadataExt;
+            Some(UnixPermissions {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mode: metadata.mode(),
+            })
+        };
+        #[cfg(not(unix))]
+        let permissions = None;
 
         return Ok(DocumentMetadata {
             path: PathBuf::from(path),
             size: metadata.len(),
+            modified: modified,
+            permissions: permissions,
         });
     }
 }
@@ -68,7 +218,7 @@ impl DocumentMetadata {
 /**
  * Represents an entire document.
  */
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Document {
     pub metadata: DocumentMetadata,
     pub title: String,
@@ -76,3 +226,274 @@ pub struct Document {
     pub preview_text: Option<String>, // a preview text to show for the document, recommended to be less than 200 chars.
     pub preview_img_path: Option<PathBuf>, // a preview image to show for the document.
 }
+
+/**
+ * Optional knobs controlling how a query is executed and how its results are ordered.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub sort: SortOrder,
+    // when set, query words also match terms within this many edits (at most 2), ranked below exact matches.
+    pub fuzzy_distance: Option<u8>,
+    pub scope: AccessScope,
+    // only match the words of the query against filenames, skipping the full-text query. Much cheaper, it gives early
+    // results while a full-text search runs.
+    #[serde(default)]
+    pub filenames_only: bool,
+    // directories whose subdirectories the directory facet counts, usually the indexed directories. Counts the
+    // directories at the top of the filesystem when empty.
+    #[serde(default)]
+    pub facet_roots: Vec<PathBuf>,
+    // stops the search once set, it then fails with `IndexError::Cancelled`.
+    #[serde(skip)]
+    pub cancelled: Option<Arc<AtomicBool>>,
+}
+
+/**
+ * Limits the documents a search can see, hits and facet counts only ever include documents within the scope.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccessScope {
+    // documents must be under one of these paths, None allows every path.
+    pub paths: Option<Vec<PathBuf>>,
+    // documents must be readable by this user according to the permissions recorded when they were indexed, None
+    // ignores permissions.
+    pub unix_user: Option<UnixUser>,
+}
+
+impl AccessScope {
+    pub fn is_unrestricted(&self) -> bool {
+        return self.paths.is_none() && self.unix_user.is_none();
+    }
+
+    /**
+     * Whether a single document is within the scope, matching the filtering applied to searches.
+     */
+    pub fn allows(&self, metadata: &DocumentMetadata) -> bool {
+        if let Some(paths) = &self.paths {
+            if !paths.iter().any(|path| metadata.path.starts_with(path)) {
+                return false;
+            }
+        }
+        if let Some(unix_user) = &self.unix_user {
+            let user_readers = unix_user.readers();
+            return match &metadata.permissions {
+                Some(permissions) => permissions
+                    .readers()
+                    .iter()
+                    .any(|reader| user_readers.contains(reader)),
+                None => false,
+            };
+        }
+        return true;
+    }
+}
+
+/**
+ * Unix identity that a user's searches are checked against, a user without a uid can only see world readable files.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UnixUser {
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+}
+
+impl UnixUser {
+    /**
+     * Terms for the files this user can read, see `UnixPermissions::readers`.
+     */
+    pub fn readers(&self) -> Vec<String> {
+        let mut readers = vec![String::from("other")];
+        if let Some(uid) = self.uid {
+            readers.push(format!("uid:{}", uid));
+        }
+        for gid in &self.gids {
+            readers.push(format!("gid:{}", gid));
+        }
+        return readers;
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Relevance,
+    Modified,
+    Size,
+    Name,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    #[serde(alias = "asc")]
+    Ascending,
+    #[serde(alias = "desc")]
+    Descending,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub field: SortField,
+    pub direction: SortDirection,
+}
+
+impl Default for SortOrder {
+    fn default() -> SortOrder {
+        return SortOrder {
+            field: SortField::Relevance,
+            direction: SortDirection::Descending,
+        };
+    }
+}
+
+impl FromStr for SortField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SortField> {
+        return match s {
+            "relevance" => Ok(SortField::Relevance),
+            "modified" => Ok(SortField::Modified),
+            "size" => Ok(SortField::Size),
+            "name" => Ok(SortField::Name),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown sort field {:?}, expected one of relevance, modified, size or name",
+                s
+            ))),
+        };
+    }
+}
+
+impl FromStr for SortDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SortDirection> {
+        return match s {
+            "asc" | "ascending" => Ok(SortDirection::Ascending),
+            "desc" | "descending" => Ok(SortDirection::Descending),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown sort direction {:?}, expected asc or desc",
+                s
+            ))),
+        };
+    }
+}
+
+/**
+ * A single document matching a query along with its relevance score.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SearchHit {
+    pub id: String, // identifies the document in urls, see `document_id`.
+    pub score: f32,
+    pub document: Document,
+}
+
+/**
+ * The number of documents matching a query that fall under a given facet (e.g. a directory or file type).
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+/**
+ * The page of hits for a query along with the total number of matching documents and drill-down facet counts.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    pub total_hits: usize,
+    pub directories: Vec<FacetCount>, // counts of matching documents by directory directly within a facet root.
+    pub file_types: Vec<FacetCount>,  // counts of matching documents by lowercased file extension.
+    pub latency_micros: u64,          // time spent executing the query and loading the hits.
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/**
+ * A file or directory within a directory listing.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrowseEntry {
+    pub id: Option<String>, // document id of a file, see `document_id`.
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    pub size: Option<u64>,     // size of a file in bytes.
+    pub modified: Option<u64>, // modification time of a file in seconds since the unix epoch.
+    pub documents: u64,        // number of indexed documents at or beneath the entry.
+}
+
+/**
+ * A page of the entries within a directory.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrowseResults {
+    pub path: Option<PathBuf>, // the directory listed, None when listing the indexed directories themselves.
+    pub entries: Vec<BrowseEntry>,
+    pub total_entries: usize,
+}
+
+/**
+ * Url safe identifier for the document at the given path, it is the path itself so it needs no lookup table.
+ */
+pub fn document_id(path: &Path) -> String {
+    return base64::encode_config(path.to_string_lossy().as_bytes(), base64::URL_SAFE_NO_PAD);
+}
+
+/**
+ * The path a document id refers to, None if the id is malformed.
+ */
+pub fn document_path(id: &str) -> Option<PathBuf> {
+    let path = base64::decode_config(id, base64::URL_SAFE_NO_PAD).ok()?;
+    return Some(PathBuf::from(String::from_utf8(path).ok()?));
+}
+
+/**
+ * Summary of a single indexing pass over the configured directories.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct IndexingPass {
+    pub started: u64, // start time in seconds since the unix epoch.
+    pub duration_millis: u64,
+    pub scanned: u64, // files visited by the walk.
+    pub indexed: u64, // files added or updated.
+    pub skipped: u64, // files that were unchanged or had no matching provider.
+    pub errors: u64,  // files or directories that could not be read.
+}
+
+/**
+ * The number of documents and bytes indexed under one of the configured directories.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RootStats {
+    pub path: PathBuf,
+    pub documents: u64,
+    pub bytes: u64,
+}
+
+/**
+ * Overview of the contents and on-disk footprint of an index.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexStats {
+    pub total_documents: u64,
+    pub roots: Vec<RootStats>,
+    pub bytes_indexed: u64, // total size of the indexed files.
+    pub invalid_documents: u64, // documents whose metadata couldn't be parsed, left out of the other counts.
+    pub segments: usize,
+    pub index_size_bytes: u64, // on-disk size of the tantivy index, 0 when held in memory.
+    pub metadata_size_bytes: u64, // on-disk size of the metadata store, 0 when held in memory.
+    pub last_indexing_pass: Option<IndexingPass>,
+    pub extensions: Vec<FacetCount>, // documents by lowercased file extension.
+    pub mime_types: Vec<FacetCount>, // documents by mime type guessed from the extension.
+}
@@ -0,0 +1,165 @@
+use crate::index::metadata_store::*;
+use crate::index::IndexResult;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const DATABASE_FILE: &str = "metadata.sqlite";
+
+// how long a writer waits on another process holding the write lock before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * Metadata store backed by a SQLite database, each table is a SQL table of (key, value) rows. Values that are valid
+ * utf-8, such as the json document metadata, are stored as text so they can be queried with SQLite's json functions.
+ */
+pub struct SqliteMetadataStore {
+    path: PathBuf,
+    // point reads go through a single shared connection, iteration and each write transaction open their own.
+    reader: Mutex<Connection>,
+}
+
+impl SqliteMetadataStore {
+    pub fn open(dir: &Path) -> IndexResult<SqliteMetadataStore> {
+        if !dir.exists() {
+            fs::create_dir(dir)?;
+        }
+
+        let path = dir.join(DATABASE_FILE);
+        let connection = open_connection(&path)?;
+        // write-ahead logging lets searches keep reading while an indexing pass holds a write transaction open.
+        connection.query_row("PRAGMA journal_mode=WAL", [], |_| Ok(()))?;
+        for table in Table::ALL {
+            connection.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY NOT NULL, value NOT NULL) WITHOUT ROWID",
+                table.name()
+            ))?;
+        }
+
+        return Ok(SqliteMetadataStore {
+            path: path,
+            reader: Mutex::new(connection),
+        });
+    }
+}
+
+impl MetadataStore for SqliteMetadataStore {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        return get(&self.reader.lock().unwrap(), table, key);
+    }
+
+    fn is_empty(&self, table: Table) -> IndexResult<bool> {
+        let reader = self.reader.lock().unwrap();
+        let first: Option<String> = reader
+            .query_row(
+                &format!("SELECT key FROM {} LIMIT 1", table.name()),
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        return Ok(first.is_none());
+    }
+
+    fn for_each(
+        &self,
+        table: Table,
+        visit: &mut dyn FnMut(&str, &[u8]) -> IndexResult<()>,
+    ) -> IndexResult<()> {
+        // iterate on a dedicated connection so visit can read from the store without deadlocking on the shared one.
+        let connection = open_connection(&self.path)?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT key, value FROM {} ORDER BY key",
+            table.name()
+        ))?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: String = row.get(0)?;
+            visit(&key, value_bytes(row.get_ref(1)?))?;
+        }
+        return Ok(());
+    }
+
+    fn begin_write(&self) -> IndexResult<Box<dyn MetadataWriteTransaction>> {
+        let connection = open_connection(&self.path)?;
+        connection.execute_batch("BEGIN IMMEDIATE")?;
+        return Ok(Box::new(SqliteWriteTransaction {
+            connection: connection,
+        }));
+    }
+
+    fn copy_to(&self, dir: &Path) -> IndexResult<()> {
+        fs::create_dir_all(dir)?;
+        let reader = self.reader.lock().unwrap();
+        reader.execute(
+            "VACUUM INTO ?",
+            params![dir.join(DATABASE_FILE).to_string_lossy()],
+        )?;
+        return Ok(());
+    }
+}
+
+/**
+ * Write transaction on its own connection, dropping it without committing rolls it back when the connection closes.
+ */
+struct SqliteWriteTransaction {
+    connection: Connection,
+}
+
+impl MetadataWriteTransaction for SqliteWriteTransaction {
+    fn get(&self, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+        return get(&self.connection, table, key);
+    }
+
+    fn put(&mut self, table: Table, key: &str, value: &[u8]) -> IndexResult<()> {
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?, ?)",
+            table.name()
+        );
+        match std::str::from_utf8(value) {
+            Ok(text) => self.connection.execute(&sql, params![key, text])?,
+            Err(_) => self.connection.execute(&sql, params![key, value])?,
+        };
+        return Ok(());
+    }
+
+    fn delete(&mut self, table: Table, key: &str) -> IndexResult<()> {
+        self.connection.execute(
+            &format!("DELETE FROM {} WHERE key = ?", table.name()),
+            params![key],
+        )?;
+        return Ok(());
+    }
+
+    fn commit(self: Box<Self>) -> IndexResult<()> {
+        self.connection.execute_batch("COMMIT")?;
+        return Ok(());
+    }
+}
+
+fn open_connection(path: &Path) -> IndexResult<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    return Ok(connection);
+}
+
+fn get(connection: &Connection, table: Table, key: &str) -> IndexResult<Option<Vec<u8>>> {
+    let value = connection
+        .query_row(
+            &format!("SELECT value FROM {} WHERE key = ?", table.name()),
+            params![key],
+            |row| Ok(value_bytes(row.get_ref(0)?).to_vec()),
+        )
+        .optional()?;
+    return Ok(value);
+}
+
+fn value_bytes(value: ValueRef<'_>) -> &[u8] {
+    return match value {
+        ValueRef::Text(text) => text,
+        ValueRef::Blob(blob) => blob,
+        _ => &[],
+    };
+}
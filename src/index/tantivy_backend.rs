@@ -1,14 +1,69 @@
-use lmdb::LmdbResultExt;
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
-use tantivy::{ReloadPolicy, TantivyError};
+use tantivy::collector::{
+    Collector, Count, FacetCollector, FacetCounts, ScoreSegmentTweaker, ScoreTweaker,
+    SegmentCollector, TopDocs,
+};
+use tantivy::directory::error::LockError;
+use tantivy::directory::{Directory, DirectoryLock, MmapDirectory, INDEX_WRITER_LOCK};
+use tantivy::fastfield::{BytesFastFieldReader, DynamicFastFieldReader, FastFieldReader};
+use tantivy::merge_policy::{LogMergePolicy, NoMergePolicy};
+use tantivy::query::{
+    AllQuery, BooleanQuery, BoostQuery, EmptyQuery, FuzzyTermQuery, Occur, PhraseQuery, Query,
+    QueryParser, Scorer, TermQuery, Weight,
+};
+use tantivy::schema::IndexRecordOption;
+use tantivy::tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer};
+use tantivy::{
+    DocAddress, DocId, DocSet, ReloadPolicy, Score, Searcher, SegmentReader, TantivyError,
+    TERMINATED,
+};
 
+use crate::index::memory_store::MemoryMetadataStore;
+use crate::index::metadata_store::*;
 use crate::index::*;
 use anyhow::{Context, Result};
-use lmdb_zero as lmdb;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::Arc;
+use std::time::Instant;
+
+// key in the meta database holding the generation of the last commit.
+const GENERATION_KEY: &str = "generation";
+
+// version of the tantivy schema built by `TantivyIndex::create`, bump whenever a field is added, removed or changed.
+// Indexes created before the version was recorded are version 1.
+const SCHEMA_VERSION: u32 = 3;
+// key in the meta database holding the schema version of the on-disk index.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+// key in the meta database holding the summary of the most recent indexing pass.
+const LAST_INDEXING_PASS_KEY: &str = "last_indexing_pass";
+
+// maximum number of facet values reported for each facet in a set of search results.
+const MAX_FACET_COUNTS: usize = 25;
+
+// documents a cancellable search visits between checks of its cancellation flag.
+const CANCELLATION_CHECK_INTERVAL: usize = 1024;
+
+// words in a filename are indexed as all of their prefixes between these lengths so partially typed words match.
+const FILENAME_TOKENIZER: &str = "filename_prefix";
+const FILENAME_MIN_PREFIX: usize = 2;
+const FILENAME_MAX_PREFIX: usize = 20;
+
+// weight of filename matches relative to matches on the full path and document contents.
+const FILENAME_BOOST: Score = 2.0;
+
+// largest edit distance supported by tantivy's levenshtein automata.
+const MAX_FUZZY_DISTANCE: u8 = 2;
+// fuzzy matches are scored as a small constant per default field matching a query word within the edit distance.
+const FUZZY_BOOST: Score = 0.1;
+// constant score for each default field matching a query word exactly when searching fuzzily. It exceeds the fuzzy
+// matches of a word in every default field together, so exact matches rank above typos however common the word is.
+const EXACT_MATCH_FLOOR: Score = 1.0;
+// words shorter than this are too ambiguous to expand with typos.
+const FUZZY_MIN_WORD_LENGTH: usize = 3;
 
 /**
  * Internal representation of the schema and fields that have been added to the index.
@@ -18,144 +73,1666 @@ struct TantivyIndexLayout {
     field_title: tantivy::schema::Field,
     field_keyword: tantivy::schema::Field,
     field_path: tantivy::schema::Field,
+    field_file_type: tantivy::schema::Field,
+    field_filename: tantivy::schema::Field,
+    field_size: tantivy::schema::Field,
+    field_modified: tantivy::schema::Field,
+    field_name: tantivy::schema::Field,
+    field_generation: tantivy::schema::Field,
+    field_readers: tantivy::schema::Field,
+}
+
+impl TantivyIndexLayout {
+    fn new() -> TantivyIndexLayout {
+        let mut schema_builder = tantivy::schema::Schema::builder();
+        let field_title = schema_builder.add_text_field("title", tantivy::schema::TEXT);
+        let field_keyword = schema_builder.add_text_field("keywords", tantivy::schema::TEXT);
+        let field_path = schema_builder.add_facet_field(
+            "path",
+            tantivy::schema::FacetOptions::default()
+                .set_stored()
+                .set_indexed(),
+        );
+        let field_file_type = schema_builder.add_facet_field(
+            "file_type",
+            tantivy::schema::FacetOptions::default().set_indexed(),
+        );
+        let field_filename = schema_builder.add_text_field(
+            "filename",
+            tantivy::schema::TextOptions::default().set_indexing_options(
+                tantivy::schema::TextFieldIndexing::default()
+                    .set_tokenizer(FILENAME_TOKENIZER)
+                    .set_index_option(IndexRecordOption::WithFreqs),
+            ),
+        );
+        let field_size = schema_builder.add_u64_field("size", tantivy::schema::FAST);
+        let field_modified = schema_builder.add_u64_field("modified", tantivy::schema::FAST);
+        let field_name = schema_builder.add_bytes_field("name", tantivy::schema::FAST);
+        let field_generation =
+            schema_builder.add_u64_field("generation", tantivy::schema::INDEXED);
+        // who can read the file per its unix permissions, see `UnixPermissions::readers`.
+        let field_readers = schema_builder.add_text_field("readers", tantivy::schema::STRING);
+
+        return TantivyIndexLayout {
+            field_title: field_title,
+            field_keyword: field_keyword,
+            field_path: field_path,
+            field_file_type: field_file_type,
+            field_filename: field_filename,
+            field_size: field_size,
+            field_modified: field_modified,
+            field_name: field_name,
+            field_generation: field_generation,
+            field_readers: field_readers,
+            schema: schema_builder.build(),
+        };
+    }
+}
+
+/**
+ * How tantivy merges segments as documents are added, unset values keep tantivy's defaults.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergePolicySettings {
+    // with merging disabled every commit leaves a new segment behind until the index is compacted.
+    #[serde(default = "MergePolicySettings::default_enabled")]
+    pub enabled: bool,
+    pub min_num_segments: Option<usize>, // fewest segments of a similar size that are merged together.
+    pub max_docs_before_merge: Option<usize>, // segments with more documents than this are never merged.
+    pub min_layer_size: Option<u32>, // segments smaller than this are all treated as the same size.
+    pub level_log_size: Option<f64>, // log of the size ratio between segments grouped into one merge.
+}
+
+impl MergePolicySettings {
+    fn default_enabled() -> bool {
+        return true;
+    }
+
+    fn build(&self) -> Box<dyn tantivy::merge_policy::MergePolicy> {
+        if !self.enabled {
+            return Box::new(NoMergePolicy);
+        }
+
+        let mut policy = LogMergePolicy::default();
+        if let Some(min_num_segments) = self.min_num_segments {
+            policy.set_min_num_segments(min_num_segments);
+        }
+        if let Some(max_docs_before_merge) = self.max_docs_before_merge {
+            policy.set_max_docs_before_merge(max_docs_before_merge);
+        }
+        if let Some(min_layer_size) = self.min_layer_size {
+            policy.set_min_layer_size(min_layer_size);
+        }
+        if let Some(level_log_size) = self.level_log_size {
+            policy.set_level_log_size(level_log_size);
+        }
+        return Box::new(policy);
+    }
+}
+
+impl Default for MergePolicySettings {
+    fn default() -> Self {
+        return MergePolicySettings {
+            enabled: true,
+            min_num_segments: None,
+            max_docs_before_merge: None,
+            min_layer_size: None,
+            level_log_size: None,
+        };
+    }
 }
 
 /**
  * Tantivy based backend for search indexing of documents.
  */
 pub struct TantivyIndex {
-    path: PathBuf,
-    path_index: PathBuf,
+    // directory holding the tantivy index and keyvalue store, None when both are held in memory.
+    path: Option<PathBuf>,
 
     layout: TantivyIndexLayout,
     index: tantivy::Index,
-    lmdb_env: Arc<lmdb::Environment>,
-    db_indexed_files: lmdb::Database<'static>,
+    // shared by all searches, reloads when a commit lands including commits made by other processes.
+    reader: tantivy::IndexReader,
+    metadata: Box<dyn MetadataStore>,
+    // whether keywords extracted from each document are kept in the metadata store's content table.
+    store_content: bool,
+    merge_policy: MergePolicySettings,
 }
 
 impl TantivyIndex {
-    pub fn create(dir: &Path) -> Result<TantivyIndex> {
+    /**
+     * Opens the index in the given directory with its metadata kept in the given backend, creating both if they don't
+     * exist.
+     */
+    pub fn create(dir: &Path, backend: MetadataBackend) -> Result<TantivyIndex> {
         // setup directory structure
         let path_index = dir.join("index");
         if !path_index.exists() {
             fs::create_dir(&path_index)?
         }
 
-        let path_kvstore = dir.join("kvstore");
-        if !path_kvstore.exists() {
-            fs::create_dir(&path_kvstore)?
+        let layout = TantivyIndexLayout::new();
+        let (index, created) = open_tantivy_index(&path_index, &layout.schema)
+            .context("failed to open the tantivy index")?;
+        let metadata = backend
+            .open(&dir.join("kvstore"))
+            .context("failed to create the keyvalue store environment.")?;
+
+        return TantivyIndex::open(Some(PathBuf::from(dir)), layout, index, created, metadata);
+    }
+
+    /**
+     * Creates an empty index held entirely in memory, useful for tests and for embedding where nothing should touch
+     * the disk.
+     */
+    pub fn create_in_memory() -> Result<TantivyIndex> {
+        let layout = TantivyIndexLayout::new();
+        let index = tantivy::Index::create_in_ram(layout.schema.clone());
+        register_tokenizers(&index);
+
+        return TantivyIndex::open(
+            None,
+            layout,
+            index,
+            true,
+            Box::new(MemoryMetadataStore::new()),
+        );
+    }
+
+    fn open(
+        path: Option<PathBuf>,
+        layout: TantivyIndexLayout,
+        index: tantivy::Index,
+        created: bool,
+        metadata: Box<dyn MetadataStore>,
+    ) -> Result<TantivyIndex> {
+        let reader =
+            open_reader(&index).context("failed to create a reader for the tantivy index")?;
+
+        let mut index = TantivyIndex {
+            path: path,
+            layout: layout,
+            index: index,
+            reader: reader,
+            metadata: metadata,
+            store_content: false,
+            merge_policy: MergePolicySettings::default(),
+        };
+
+        index
+            .upgrade_schema(created)
+            .context("failed to upgrade the index schema")?;
+        index
+            .recover_interrupted_commit()
+            .context("failed to recover from an interrupted commit")?;
+        return Ok(index);
+    }
+
+    /**
+     * Enables keeping the keywords extracted from newly added documents in the keyvalue store so that rebuilding the index
+     * doesn't require re-reading the files they came from.
+     */
+    pub fn set_store_content(&mut self, store_content: bool) {
+        self.store_content = store_content;
+    }
+
+    /**
+     * Sets the merge policy used by writers created from now on.
+     */
+    pub fn set_merge_policy(&mut self, merge_policy: MergePolicySettings) {
+        self.merge_policy = merge_policy;
+    }
+
+    /**
+     * Merges every segment into one, dropping deleted documents along the way, then removes the index files that are no
+     * longer in use. Returns the number of segments there were before merging.
+     */
+    pub fn force_merge(&self) -> IndexResult<usize> {
+        let mut tantivy_writer = self.writer()?;
+        let segment_ids = self.index.searchable_segment_ids()?;
+        let has_deletes = self
+            .reader
+            .searcher()
+            .segment_readers()
+            .iter()
+            .any(|segment_reader| segment_reader.has_deletes());
+        if segment_ids.len() > 1 || has_deletes {
+            futures::executor::block_on(tantivy_writer.merge(&segment_ids))?;
         }
+        futures::executor::block_on(tantivy_writer.garbage_collect_files())?;
+        tantivy_writer.wait_merging_threads()?;
+        self.reader.reload()?;
+        return Ok(segment_ids.len());
+    }
 
-        // configure tantivy
-        let mut schema_builder = tantivy::schema::Schema::builder();
-        let field_title = schema_builder.add_text_field("title", tantivy::schema::TEXT);
-        let field_keyword = schema_builder.add_text_field("keywords", tantivy::schema::TEXT);
-        let field_path = schema_builder.add_facet_field(
-            "path",
-            tantivy::schema::FacetOptions::default()
-                .set_stored()
-                .set_indexed(),
+    /**
+     * Opens a writer on the tantivy index using the configured merge policy.
+     */
+    fn writer(&self) -> IndexResult<tantivy::IndexWriter> {
+        let tantivy_writer = self.index.writer(50_000_000 /* 50 MB heap size */)?;
+        tantivy_writer.set_merge_policy(self.merge_policy.build());
+        return Ok(tantivy_writer);
+    }
+
+    /**
+     * Writes a compacted copy of the keyvalue store into the given directory. Opening an index over the copy rebuilds
+     * tantivy from it, including any stored content, without touching the indexed filesystem.
+     */
+    pub fn copy_keyvalue_store(&self, dir: &Path) -> IndexResult<()> {
+        return self.metadata.copy_to(dir);
+    }
+
+    /**
+     * Fails unless the keyvalue store holds the content of every indexed document, which a rebuild from the store alone
+     * needs to reproduce the index rather than one missing the contents of some documents.
+     */
+    pub fn ensure_content_stored(&self) -> IndexResult<()> {
+        let mut without_content = 0;
+        self.metadata.for_each(Table::IndexedFiles, &mut |path, _| {
+            if self.metadata.get(Table::Content, path)?.is_none() {
+                without_content += 1;
+            }
+            return Ok(());
+        })?;
+        if without_content > 0 {
+            return Err(IndexError::ContentNotStored(without_content));
+        }
+        return Ok(());
+    }
+
+    /**
+     * Brings the on-disk tantivy index up to the current schema version, running registered migrations where possible
+     * and otherwise rebuilding the index from the metadata store.
+     */
+    fn upgrade_schema(&mut self, created: bool) -> IndexResult<()> {
+        let stored_version = self
+            .metadata
+            .get(Table::Meta, SCHEMA_VERSION_KEY)?
+            .and_then(|version| String::from_utf8_lossy(&version).parse::<u32>().ok());
+        let has_metadata = !self.metadata.is_empty(Table::IndexedFiles)?;
+
+        if created {
+            // the tantivy index went missing, repopulate it from what the metadata store knows about.
+            if has_metadata {
+                println!("tantivy index is missing, rebuilding it from the keyvalue store");
+                self.rebuild_from_metadata()?;
+            }
+        } else {
+            let mut version = stored_version.unwrap_or(1);
+            if version > SCHEMA_VERSION {
+                return Err(IndexError::UnsupportedSchemaVersion(version, SCHEMA_VERSION));
+            }
+
+            while version < SCHEMA_VERSION {
+                let migration = SCHEMA_MIGRATIONS
+                    .iter()
+                    .find(|migration| migration.from_version == version);
+                match migration {
+                    Some(migration) => {
+                        println!("migrating index schema from version {}", version);
+                        (migration.migrate)(self)?;
+                        version += 1;
+                    }
+                    None => break,
+                }
+            }
+
+            if version != SCHEMA_VERSION || self.index.schema() != self.layout.schema {
+                println!(
+                    "index schema version {} does not match version {}, rebuilding it from the keyvalue store",
+                    version, SCHEMA_VERSION
+                );
+                self.rebuild_from_metadata()?;
+            }
+        }
+
+        if stored_version != Some(SCHEMA_VERSION) {
+            let mut txn = self.metadata.begin_write()?;
+            txn.put(
+                Table::Meta,
+                SCHEMA_VERSION_KEY,
+                SCHEMA_VERSION.to_string().as_bytes(),
+            )?;
+            txn.commit()?;
+        }
+        return Ok(());
+    }
+
+    /**
+     * Builds a fresh tantivy index with the current schema from the document metadata store and swaps it in place of the
+     * existing one. Documents indexed without store_content enabled have no stored keywords, they are rebuilt from their
+     * metadata alone so their names stay searchable and marked unextracted so that the next indexing pass re-indexes them.
+     */
+    fn rebuild_from_metadata(&mut self) -> IndexResult<()> {
+        let path_rebuild = self.path.as_ref().map(|path| path.join("index.rebuild"));
+        let rebuilt_index = match &path_rebuild {
+            Some(path_rebuild) => {
+                if path_rebuild.exists() {
+                    fs::remove_dir_all(path_rebuild)?;
+                }
+                fs::create_dir(path_rebuild)?;
+                open_tantivy_index(path_rebuild, &self.layout.schema)?.0
+            }
+            None => {
+                let index = tantivy::Index::create_in_ram(self.layout.schema.clone());
+                register_tokenizers(&index);
+                index
+            }
+        };
+
+        // documents whose keywords were never stored, their contents can't be rebuilt without re-reading the files.
+        let mut without_content: Vec<String> = Vec::new();
+        {
+            let mut tantivy_writer = rebuilt_index.writer(50_000_000 /* 50 MB heap size */)?;
+            let generation = self.metadata_generation()?;
+            let mut rebuilt_documents = 0;
+            self.metadata.for_each(Table::IndexedFiles, &mut |path, value| {
+                let keywords = match self.read_content(path)? {
+                    Some(keywords) => keywords,
+                    None => {
+                        without_content.push(path.to_string());
+                        Vec::new()
+                    }
+                };
+                let document: Document = match serde_json::from_slice(value) {
+                    Ok(document) => document,
+                    Err(e) => {
+                        println!(
+                            "skipping {:?} during rebuild, failed to parse its metadata: {}",
+                            path, e
+                        );
+                        return Ok(());
+                    }
+                };
+                tantivy_writer.add_document(self.build_document(&document, &keywords, generation)?);
+                rebuilt_documents += 1;
+                return Ok(());
+            })?;
+
+            commit_tantivy(&mut tantivy_writer, generation)?;
+            tantivy_writer.wait_merging_threads()?;
+            println!("rebuilt the tantivy index with {} documents", rebuilt_documents);
+        }
+
+        if !without_content.is_empty() {
+            let mut txn = self.metadata.begin_write()?;
+            for path in &without_content {
+                txn.put(Table::Unextracted, path, &[])?;
+            }
+            txn.commit()?;
+            println!(
+                "rebuilt {} documents without stored content from their metadata, the next indexing pass re-indexes them",
+                without_content.len()
+            );
+        }
+
+        match (&self.path, &path_rebuild) {
+            (Some(path), Some(path_rebuild)) => {
+                // swap the rebuilt index into place.
+                let path_index = path.join("index");
+                let path_old = path.join("index.old");
+                if path_old.exists() {
+                    fs::remove_dir_all(&path_old)?;
+                }
+                fs::rename(&path_index, &path_old)?;
+                fs::rename(path_rebuild, &path_index)?;
+
+                let (index, _) = open_tantivy_index(&path_index, &self.layout.schema)?;
+                self.reader = open_reader(&index)?;
+                self.index = index;
+                fs::remove_dir_all(&path_old)?;
+            }
+            _ => {
+                self.reader = open_reader(&rebuilt_index)?;
+                self.index = rebuilt_index;
+            }
+        }
+        return Ok(());
+    }
+
+    /**
+     * Reads the keywords stored for the document at the given path, None if they weren't stored.
+     */
+    fn read_content(&self, path: &str) -> IndexResult<Option<Vec<String>>> {
+        return match self.metadata.get(Table::Content, path)? {
+            Some(content) => Ok(Some(serde_json::from_slice(
+                &lz4_flex::decompress_size_prepended(&content)?,
+            )?)),
+            None => Ok(None),
+        };
+    }
+
+    /**
+     * Converts a document and the keywords extracted from it into a tantivy document stamped with the given generation.
+     */
+    fn build_document(
+        &self,
+        doc: &Document,
+        keywords: &Vec<String>,
+        generation: u64,
+    ) -> IndexResult<tantivy::Document> {
+        let path = doc.metadata.path.to_string_lossy();
+        let mut tantivy_doc = tantivy::doc! {
+            self.layout.field_title => doc.title.clone()
+        };
+        tantivy_doc.add_facet(
+            self.layout.field_path,
+            tantivy::schema::Facet::from_text(&path)?,
         );
-        let schema = schema_builder.build();
+        let filename = doc.metadata.path.file_name().unwrap_or_default().to_string_lossy();
+        for word in filename_words(&filename) {
+            tantivy_doc.add_text(self.layout.field_filename, word);
+        }
+        tantivy_doc.add_u64(self.layout.field_generation, generation);
+        tantivy_doc.add_u64(self.layout.field_size, doc.metadata.size);
+        tantivy_doc.add_u64(self.layout.field_modified, doc.metadata.modified);
+        tantivy_doc.add_bytes(self.layout.field_name, filename.to_lowercase());
+        if let Some(extension) = doc.metadata.path.extension() {
+            tantivy_doc.add_facet(
+                self.layout.field_file_type,
+                tantivy::schema::Facet::from_path(vec![extension
+                    .to_string_lossy()
+                    .to_lowercase()]),
+            );
+        }
 
-        let index = tantivy::Index::create_in_dir(&path_index, schema.clone())
-            .or_else(|error| match error {
-                TantivyError::IndexAlreadyExists => Ok(tantivy::Index::open_in_dir(&path_index)?),
-                _ => Err(error),
-            })
-            .context("failed to open the tantivy index")?;
+        if let Some(permissions) = &doc.metadata.permissions {
+            for reader in permissions.readers() {
+                tantivy_doc.add_text(self.layout.field_readers, reader);
+            }
+        }
 
-        // configure lmdb as a keyvalue store. We're joining the two databases here.
-        let lmdb_env = Arc::new(unsafe {
-            let GB = 1024 * 1024 * 1024;
-            let mut builder = lmdb::EnvBuilder::new().unwrap();
-            builder.set_maxdbs(2)?;
-            builder.set_mapsize(128 * GB);
-            builder
-                .open(
-                    &path_kvstore.to_string_lossy(),
-                    lmdb::open::Flags::empty(),
-                    0o600,
-                )
-                .context("failed to create the keyvalue store environment.")?
-        });
+        for keyword in keywords {
+            tantivy_doc.add_text(self.layout.field_keyword, keyword);
+        }
+        return Ok(tantivy_doc);
+    }
 
-        return Ok(TantivyIndex {
-            path: PathBuf::from(dir),
-            path_index: PathBuf::from(&path_index),
+    /**
+     * Tantivy and the metadata store are committed one after the other, each stamped with the same generation. If the
+     * process died between the two, or the second commit failed, whichever store is ahead is rolled back to the
+     * generation of the other.
+     */
+    fn recover_interrupted_commit(&self) -> IndexResult<()> {
+        if self.tantivy_generation()? == self.metadata_generation()? {
+            return Ok(());
+        }
 
-            layout: TantivyIndexLayout {
-                field_title: field_title,
-                field_keyword: field_keyword,
-                field_path: field_path,
-                schema: schema.clone(),
-            },
+        // holding the tantivy writer lock guarantees no other process is midway through a commit.
+        let mut tantivy_writer = match self.index.writer(50_000_000 /* 50 MB heap size */) {
+            Ok(writer) => writer,
+            Err(TantivyError::LockFailure(_, _)) => {
+                println!("index is locked by another writer, skipping commit recovery");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let tantivy_generation = self.tantivy_generation()?;
+        let metadata_generation = self.metadata_generation()?;
 
-            index: index,
-            lmdb_env: lmdb_env.clone(),
-            db_indexed_files: lmdb::Database::open(
-                lmdb_env.clone(),
-                Some("indexed_files"),
-                &lmdb::DatabaseOptions::create_map::<str>(),
-            )
-            .context("failed to create keyvalue store tracking indexed files")?,
-        });
+        if tantivy_generation > metadata_generation {
+            println!(
+                "tantivy is at generation {} but the metadata store is at {}, rolling back tantivy",
+                tantivy_generation, metadata_generation
+            );
+            for generation in metadata_generation + 1..=tantivy_generation {
+                tantivy_writer.delete_term(tantivy::Term::from_field_u64(
+                    self.layout.field_generation,
+                    generation,
+                ));
+            }
+            commit_tantivy(&mut tantivy_writer, metadata_generation)?;
+            self.reader.reload()?;
+        } else if metadata_generation > tantivy_generation {
+            println!(
+                "the metadata store is at generation {} but tantivy is at {}, rolling back the metadata store",
+                metadata_generation, tantivy_generation
+            );
+            let mut txn = self.metadata.begin_write()?;
+            for generation in tantivy_generation + 1..=metadata_generation {
+                let journal_key = generation.to_string();
+                let added_paths: Vec<String> = match txn.get(Table::Journal, &journal_key)? {
+                    Some(journal) => serde_json::from_slice(&journal)?,
+                    None => {
+                        println!("no journal found for generation {}", generation);
+                        Vec::new()
+                    }
+                };
+                for path in added_paths {
+                    txn.delete(Table::IndexedFiles, &path)?;
+                    txn.delete(Table::Content, &path)?;
+                }
+                txn.delete(Table::Journal, &journal_key)?;
+            }
+            txn.put(
+                Table::Meta,
+                GENERATION_KEY,
+                tantivy_generation.to_string().as_bytes(),
+            )?;
+            txn.commit()?;
+        }
+        return Ok(());
+    }
+
+    /**
+     * Generation of the last commit to tantivy, indexes written before generations were introduced are generation 0.
+     */
+    fn tantivy_generation(&self) -> IndexResult<u64> {
+        let payload = self.index.load_metas()?.payload;
+        return Ok(payload
+            .and_then(|payload| payload.parse::<u64>().ok())
+            .unwrap_or(0));
+    }
+
+    /**
+     * Generation of the last commit to the metadata store.
+     */
+    fn metadata_generation(&self) -> IndexResult<u64> {
+        return Ok(parse_generation(
+            self.metadata.get(Table::Meta, GENERATION_KEY)?,
+        ));
+    }
+
+    /**
+     * Opens both stores for writing, first rolling back a commit that was interrupted, and returns the generation the
+     * next commit is stamped with.
+     */
+    fn begin_commit(
+        &self,
+    ) -> IndexResult<(tantivy::IndexWriter, Box<dyn MetadataWriteTransaction>, u64)> {
+        // a failed commit leaves tantivy ahead while this process keeps running, not just after a crash.
+        self.recover_interrupted_commit()?;
+        let tantivy_writer = self.writer()?;
+        let txn = self.metadata.begin_write()?;
+        // never reuse a generation tantivy has already seen, the rollback could then no longer tell the commits apart.
+        let generation = self
+            .tantivy_generation()?
+            .max(parse_generation(txn.get(Table::Meta, GENERATION_KEY)?))
+            + 1;
+        return Ok((tantivy_writer, txn, generation));
+    }
+
+    /**
+     * Commits tantivy and then the metadata store stamped with the given generation. The metadata transaction records
+     * the paths it adds so that it can be rolled back if tantivy's commit turns out to be lost.
+     */
+    fn commit_generation(
+        &self,
+        tantivy_writer: &mut tantivy::IndexWriter,
+        mut txn: Box<dyn MetadataWriteTransaction>,
+        generation: u64,
+        added_paths: &Vec<String>,
+    ) -> IndexResult<()> {
+        stamp_generation(txn.as_mut(), generation, added_paths)?;
+        commit_tantivy(tantivy_writer, generation)?;
+        // make the commit visible to searches right away rather than waiting on the file watcher.
+        self.reader.reload()?;
+
+        txn.commit()?;
+        return Ok(());
+    }
+}
+
+/**
+ * An in-place upgrade of the on-disk index from one schema version to the next. Tantivy can't add fields to an
+ * existing index so most schema changes can't be migrated in place, without a registered migration the index is
+ * rebuilt from the metadata store instead.
+ */
+struct SchemaMigration {
+    from_version: u32,
+    migrate: fn(&TantivyIndex) -> IndexResult<()>,
+}
+
+const SCHEMA_MIGRATIONS: &[SchemaMigration] = &[];
+
+/**
+ * Opens the tantivy index in the given directory, creating it with the schema if there is none. Also returns whether the
+ * index was newly created.
+ */
+fn open_tantivy_index(
+    path: &Path,
+    schema: &tantivy::schema::Schema,
+) -> IndexResult<(tantivy::Index, bool)> {
+    let (index, created) = match tantivy::Index::create_in_dir(path, schema.clone()) {
+        Ok(index) => (index, true),
+        Err(TantivyError::IndexAlreadyExists) => (tantivy::Index::open_in_dir(path)?, false),
+        Err(e) => return Err(e.into()),
+    };
+    register_tokenizers(&index);
+    return Ok((index, created));
+}
+
+/**
+ * Takes the tantivy writer lock on the index kept in the given directory so that no indexing pass changes it until the
+ * lock is dropped. None if another writer holds it.
+ */
+pub fn lock_index_writer(path: &Path) -> IndexResult<Option<DirectoryLock>> {
+    let directory = MmapDirectory::open(path.join("index")).map_err(TantivyError::from)?;
+    return match directory.acquire_lock(&INDEX_WRITER_LOCK) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(LockError::LockBusy) => Ok(None),
+        Err(LockError::IoError(e)) => Err(e.into()),
+    };
+}
+
+fn register_tokenizers(index: &tantivy::Index) {
+    index.tokenizers().register(
+        FILENAME_TOKENIZER,
+        TextAnalyzer::from(NgramTokenizer::prefix_only(
+            FILENAME_MIN_PREFIX,
+            FILENAME_MAX_PREFIX,
+        ))
+        .filter(LowerCaser),
+    );
+}
+
+fn open_reader(index: &tantivy::Index) -> IndexResult<tantivy::IndexReader> {
+    return Ok(index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::OnCommit)
+        .try_into()?);
+}
+
+/**
+ * Commits the pending changes to tantivy with the generation as the commit payload.
+ */
+fn commit_tantivy(tantivy_writer: &mut tantivy::IndexWriter, generation: u64) -> IndexResult<()> {
+    let mut prepared_commit = tantivy_writer.prepare_commit()?;
+    prepared_commit.set_payload(&generation.to_string());
+    prepared_commit.commit()?;
+    return Ok(());
+}
+
+/**
+ * Records the generation in a metadata transaction along with the journal of the paths it adds.
+ */
+fn stamp_generation(
+    txn: &mut dyn MetadataWriteTransaction,
+    generation: u64,
+    added_paths: &Vec<String>,
+) -> IndexResult<()> {
+    txn.put(
+        Table::Meta,
+        GENERATION_KEY,
+        generation.to_string().as_bytes(),
+    )?;
+    txn.put(
+        Table::Journal,
+        &generation.to_string(),
+        serde_json::to_string(added_paths)?.as_bytes(),
+    )?;
+    // only the latest commit can ever need to be rolled back.
+    txn.delete(Table::Journal, &(generation - 1).to_string())?;
+    return Ok(());
+}
+
+fn parse_generation(generation: Option<Vec<u8>>) -> u64 {
+    return generation
+        .and_then(|generation| String::from_utf8_lossy(&generation).parse::<u64>().ok())
+        .unwrap_or(0);
+}
+
+/**
+ * Disagreements found between the tantivy index and the metadata store by `TantivyIndex::check`.
+ */
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    pub tantivy_documents: usize,
+    pub metadata_documents: usize,
+    pub missing_metadata: Vec<String>, // paths indexed in tantivy without an entry in the metadata store.
+    pub missing_from_index: Vec<String>, // paths with stored metadata that are not indexed in tantivy.
+    pub invalid_metadata: Vec<String>, // paths whose stored metadata fails to deserialize.
+    pub missing_on_disk: Vec<String>,  // paths tracked by the index that no longer exist.
+    pub repaired: bool,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        return self.missing_metadata.is_empty()
+            && self.missing_from_index.is_empty()
+            && self.invalid_metadata.is_empty()
+            && self.missing_on_disk.is_empty();
+    }
+}
+
+impl TantivyIndex {
+    /**
+     * Cross checks the paths indexed in tantivy against the metadata store. With `repair` set every
+     * inconsistent path is dropped from both stores so that the next indexing pass re-indexes it if it still exists.
+     */
+    pub fn check(&self, repair: bool) -> IndexResult<ConsistencyReport> {
+        let mut report = ConsistencyReport::default();
+
+        // collect every live path indexed in tantivy.
+        let searcher = self.reader.searcher();
+        let mut tantivy_paths: HashSet<String> = HashSet::new();
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            for doc_id in segment_reader.doc_ids_alive() {
+                let doc = searcher.doc(DocAddress::new(segment_ord as u32, doc_id))?;
+                let path = doc
+                    .get_first(self.layout.field_path)
+                    .and_then(|path_field| path_field.path())
+                    .ok_or(IndexError::MissingField("path"))?;
+                tantivy_paths.insert(path);
+            }
+        }
+        report.tantivy_documents = tantivy_paths.len();
+
+        // walk the metadata store checking it against tantivy and the filesystem.
+        let mut metadata_paths: HashSet<String> = HashSet::new();
+        self.metadata.for_each(Table::IndexedFiles, &mut |path, value| {
+            let path = path.to_string();
+            if !tantivy_paths.contains(&path) {
+                report.missing_from_index.push(path.clone());
+            }
+            if serde_json::from_slice::<Document>(value).is_err() {
+                report.invalid_metadata.push(path.clone());
+            }
+            if !Path::new(&path).exists() {
+                report.missing_on_disk.push(path.clone());
+            }
+            metadata_paths.insert(path);
+            return Ok(());
+        })?;
+        report.metadata_documents = metadata_paths.len();
+
+        for path in &tantivy_paths {
+            if !metadata_paths.contains(path) {
+                report.missing_metadata.push(path.clone());
+                if !Path::new(path).exists() {
+                    report.missing_on_disk.push(path.clone());
+                }
+            }
+        }
+
+        for paths in [
+            &mut report.missing_metadata,
+            &mut report.missing_from_index,
+            &mut report.invalid_metadata,
+            &mut report.missing_on_disk,
+        ] {
+            paths.sort();
+            paths.dedup();
+        }
+
+        if repair && !report.is_consistent() {
+            let mut to_remove: Vec<&String> = Vec::new();
+            to_remove.extend(&report.missing_metadata);
+            to_remove.extend(&report.missing_from_index);
+            to_remove.extend(&report.invalid_metadata);
+            to_remove.extend(&report.missing_on_disk);
+            self.remove_paths(&to_remove)?;
+            report.repaired = true;
+        }
+
+        return Ok(report);
+    }
+
+    /**
+     * Drops the given paths from both tantivy and the metadata store.
+     */
+    fn remove_paths(&self, paths: &Vec<&String>) -> IndexResult<()> {
+        let (mut tantivy_writer, mut txn, generation) = self.begin_commit()?;
+        for path in paths {
+            tantivy_writer.delete_term(tantivy::Term::from_facet(
+                self.layout.field_path,
+                &tantivy::schema::Facet::from_text(path)?,
+            ));
+            txn.delete(Table::IndexedFiles, path)?;
+            txn.delete(Table::Content, path)?;
+            txn.delete(Table::Unextracted, path)?;
+        }
+
+        return self.commit_generation(&mut tantivy_writer, txn, generation, &Vec::new());
     }
 }
 
 impl WritableIndex for TantivyIndex {
-    fn begin_add_documents<'a>(&'a mut self) -> Result<Box<dyn IndexWriter + 'a>> {
+    fn begin_add_documents<'a>(&'a self) -> IndexResult<Box<dyn IndexWriter + 'a>> {
         return Ok(Box::new(TantivyIndexWriter::create(self)?));
     }
 }
 
 impl SearchableIndex for TantivyIndex {
+    fn get_document(&self, path: &Path) -> IndexResult<Option<Document>> {
+        return match self.metadata.get(Table::IndexedFiles, &path.to_string_lossy())? {
+            Some(document) => Ok(Some(serde_json::from_slice(&document)?)),
+            None => Ok(None),
+        };
+    }
+
+    fn last_indexing_pass(&self) -> IndexResult<Option<IndexingPass>> {
+        return match self.metadata.get(Table::Meta, LAST_INDEXING_PASS_KEY)? {
+            Some(pass) => Ok(Some(serde_json::from_slice(&pass)?)),
+            None => Ok(None),
+        };
+    }
+
+    fn browse(
+        &self,
+        path: &Path,
+        limit: usize,
+        offset: usize,
+        sort: SortOrder,
+        scope: &AccessScope,
+    ) -> IndexResult<BrowseResults> {
+        if sort.field == SortField::Relevance {
+            return Err(IndexError::InvalidOption(String::from(
+                "directory listings can't be sorted by relevance",
+            )));
+        }
+
+        // counting the path facet under the directory gives each child along with the documents beneath it.
+        let facet = path_facet(path)?;
+        let mut children_collector = FacetCollector::for_field(self.layout.field_path);
+        children_collector.add_facet(facet.clone());
+        // documents are indexed under every directory above them, so only those beneath the directory are visited.
+        let directory_query: Box<dyn Query> = if facet.is_root() {
+            Box::new(AllQuery)
+        } else {
+            Box::new(TermQuery::new(
+                tantivy::Term::from_facet(self.layout.field_path, &facet),
+                IndexRecordOption::Basic,
+            ))
+        };
+        let query = self.with_scope(directory_query, scope)?;
+        let children = self
+            .reader
+            .searcher()
+            .search(query.as_ref(), &children_collector)?;
+
+        let mut entries: Vec<BrowseEntry> = Vec::new();
+        for (child, count) in children.get(facet) {
+            let child_path = child.to_path_string();
+            let name = child.to_path().last().unwrap_or(&"").to_string();
+            // children with metadata of their own are indexed files, anything else is a directory.
+            entries.push(match self.metadata.get(Table::IndexedFiles, &child_path)? {
+                Some(document) => {
+                    let document: Document = match serde_json::from_slice(&document) {
+                        Ok(document) => document,
+                        Err(e) => {
+                            println!(
+                                "skipping entry {:?}, failed to parse its metadata: {}",
+                                child_path, e
+                            );
+                            continue;
+                        }
+                    };
+                    BrowseEntry {
+                        id: Some(document_id(&document.metadata.path)),
+                        name: name,
+                        path: document.metadata.path,
+                        kind: EntryKind::File,
+                        size: Some(document.metadata.size),
+                        modified: Some(document.metadata.modified),
+                        documents: count,
+                    }
+                }
+                None => BrowseEntry {
+                    id: None,
+                    name: name,
+                    path: PathBuf::from(child_path),
+                    kind: EntryKind::Directory,
+                    size: None,
+                    modified: None,
+                    documents: count,
+                },
+            });
+        }
+
+        entries.sort_by(|a, b| {
+            let ordering = match sort.field {
+                SortField::Size => a.size.cmp(&b.size),
+                SortField::Modified => a.modified.cmp(&b.modified),
+                _ => Ordering::Equal,
+            }
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+            let ordering = match sort.direction {
+                SortDirection::Ascending => ordering,
+                SortDirection::Descending => ordering.reverse(),
+            };
+            // directories are always listed before files.
+            return (a.kind == EntryKind::File)
+                .cmp(&(b.kind == EntryKind::File))
+                .then(ordering);
+        });
+
+        let total_entries = entries.len();
+        return Ok(BrowseResults {
+            path: Some(PathBuf::from(path)),
+            entries: entries.into_iter().skip(offset).take(limit).collect(),
+            total_entries: total_entries,
+        });
+    }
+
+    fn stats(&self, roots: &Vec<PathBuf>) -> IndexResult<IndexStats> {
+        let searcher = self.reader.searcher();
+        let mut root_stats: Vec<RootStats> = roots
+            .iter()
+            .map(|root| RootStats {
+                path: root.clone(),
+                documents: 0,
+                bytes: 0,
+            })
+            .collect();
+        let mut bytes_indexed = 0;
+        let mut extensions: HashMap<String, u64> = HashMap::new();
+        let mut mime_types: HashMap<String, u64> = HashMap::new();
+        let mut invalid_documents = 0;
+        self.metadata.for_each(Table::IndexedFiles, &mut |path, value| {
+            let doc: Document = match serde_json::from_slice(value) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    println!(
+                        "skipping {:?} in stats, failed to parse its metadata: {}",
+                        path, e
+                    );
+                    invalid_documents += 1;
+                    return Ok(());
+                }
+            };
+            let path = &doc.metadata.path;
+            bytes_indexed += doc.metadata.size;
+            for root in root_stats.iter_mut() {
+                if path.starts_with(&root.path) {
+                    root.documents += 1;
+                    root.bytes += doc.metadata.size;
+                }
+            }
+
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| String::from("(none)"));
+            *extensions.entry(extension).or_insert(0) += 1;
+            let mime_type = mime_guess::from_path(path).first_or_octet_stream();
+            *mime_types
+                .entry(mime_type.essence_str().to_string())
+                .or_insert(0) += 1;
+            return Ok(());
+        })?;
+
+        let last_indexing_pass = self.last_indexing_pass()?;
+        let (index_size_bytes, metadata_size_bytes) = match &self.path {
+            Some(path) => (
+                dir_size(&path.join("index"))?,
+                dir_size(&path.join("kvstore"))?,
+            ),
+            None => (0, 0),
+        };
+
+        return Ok(IndexStats {
+            total_documents: searcher.num_docs(),
+            roots: root_stats,
+            bytes_indexed: bytes_indexed,
+            invalid_documents: invalid_documents,
+            segments: searcher.segment_readers().len(),
+            index_size_bytes: index_size_bytes,
+            metadata_size_bytes: metadata_size_bytes,
+            last_indexing_pass: last_indexing_pass,
+            extensions: largest_counts(extensions),
+            mime_types: largest_counts(mime_types),
+        });
+    }
+
     fn search(
         &self,
         query: &str,
         result_limit: usize,
         result_offset: usize,
-    ) -> Result<Vec<Document>> {
-        let reader = self
-            .index
-            .reader_builder()
-            .reload_policy(ReloadPolicy::OnCommit)
-            .try_into()
-            .unwrap();
-        let searcher = reader.searcher();
-        let query_parser = QueryParser::for_index(
-            &self.index,
-            vec![self.layout.field_title, self.layout.field_keyword],
-        );
-        let query = query_parser.parse_query(query).unwrap();
-        let top_docs = searcher
-            .search(
+        options: &SearchOptions,
+    ) -> IndexResult<SearchResults> {
+        if result_limit == 0 {
+            return Err(IndexError::InvalidOption(String::from(
+                "result limit must be at least 1",
+            )));
+        }
+
+        let started = Instant::now();
+        let searcher = self.reader.searcher();
+        let query_parser = QueryParser::for_index(&self.index, self.default_fields());
+        let parsed_query = query_parser.parse_query(query)?;
+        let query = if options.filenames_only {
+            self.expand_words(parsed_query.as_ref(), &|_, words| {
+                self.filename_query(words).unwrap_or(Box::new(EmptyQuery))
+            })
+        } else {
+            let mut content_query = parsed_query;
+            if let Some(distance) = options.fuzzy_distance {
+                content_query = self.with_fuzzy_matches(content_query.as_ref(), distance)?;
+            }
+            self.with_filename_matches(content_query.as_ref())
+        };
+        let query = self.with_scope(query, &options.scope)?;
+        let facet_roots = facet_roots(&options.facet_roots)?;
+
+        let top_docs_collector = TopDocs::with_limit(result_limit).and_offset(result_offset);
+        let (total_hits, top_docs, directory_counts, file_type_counts) = if options.sort
+            == SortOrder::default()
+        {
+            self.collect(
+                &searcher,
                 &query,
-                &TopDocs::with_limit(result_limit).and_offset(result_offset),
-            )
-            .unwrap();
+                &facet_roots,
+                &options.cancelled,
+                top_docs_collector,
+            )?
+        } else {
+            // sorting on anything other than descending relevance reads the sort key from a fast field,
+            // the relevance score is carried along to break ties and to be reported with each hit.
+            let (total_hits, sorted_docs, directory_counts, file_type_counts) = self.collect(
+                &searcher,
+                &query,
+                &facet_roots,
+                &options.cancelled,
+                top_docs_collector.tweak_score(FastFieldSort {
+                    sort: options.sort,
+                    field_size: self.layout.field_size,
+                    field_modified: self.layout.field_modified,
+                    field_name: self.layout.field_name,
+                }),
+            )?;
+            let top_docs = sorted_docs
+                .into_iter()
+                .map(|(key, doc_address)| (key.score, doc_address))
+                .collect();
+            (total_hits, top_docs, directory_counts, file_type_counts)
+        };
+
+        let mut hits: Vec<SearchHit> = Vec::new();
 
-        let mut results: Vec<Document> = Vec::new();
+        for (score, doc_address) in top_docs {
+            let retrieved_doc = searcher.doc(doc_address)?;
+            let path = retrieved_doc
+                .get_first(self.layout.field_path)
+                .and_then(|path_field| path_field.path())
+                .ok_or(IndexError::MissingField("path"))?;
 
-        let indexed_files_read_txn = lmdb::ReadTransaction::new(self.lmdb_env.clone())?;
-        let indexed_files_reader = indexed_files_read_txn.access();
+            // the two stores are committed separately so a hit may briefly (or after a crash) have no metadata,
+            // skip it rather than failing the whole query.
+            let document_metadata_json = self.metadata.get(Table::IndexedFiles, &path)?;
+            let document_metadata_json = match document_metadata_json {
+                Some(json) => json,
+                None => {
+                    println!("skipping hit {:?}, no metadata found for the document", path);
+                    continue;
+                }
+            };
 
-        for (_score, doc_address) in top_docs {
-            let retrieved_doc = searcher.doc(doc_address).unwrap();
-            let path_field = retrieved_doc.get_first(self.layout.field_path).unwrap();
-            let path = path_field.path().unwrap();
+            let document: Document = match serde_json::from_slice(&document_metadata_json) {
+                Ok(document) => document,
+                Err(e) => {
+                    println!("skipping hit {:?}, failed to parse its metadata: {}", path, e);
+                    continue;
+                }
+            };
+            hits.push(SearchHit {
+                id: document_id(&document.metadata.path),
+                score: score,
+                document: document,
+            });
+        }
 
-            let document_metadata_json: &str = indexed_files_reader
-                .get(&&self.db_indexed_files, path.as_bytes())
-                .expect("failed to find metadata for document. Index is in corrupt state.");
+        return Ok(SearchResults {
+            hits: hits,
+            total_hits: total_hits,
+            directories: self.directory_facet_counts(&directory_counts, &facet_roots)?,
+            file_types: top_level_facet_counts(&file_type_counts),
+            latency_micros: started.elapsed().as_micros() as u64,
+        });
+    }
+}
 
-            let document: Document =
-                serde_json::from_str(document_metadata_json).expect("failed to parse document");
-            results.push(document);
+impl TantivyIndex {
+    /**
+     * Extends a parsed query to also match each word it searches for in the title and keywords within the given edit
+     * distance. Exact matches are given a score floor above anything the typo matches of a word can add, so they
+     * continue to rank above typo matches.
+     */
+    fn with_fuzzy_matches(&self, query: &dyn Query, distance: u8) -> IndexResult<Box<dyn Query>> {
+        if distance > MAX_FUZZY_DISTANCE {
+            return Err(IndexError::InvalidOption(format!(
+                "fuzzy distance must be <= {}, found: {}",
+                MAX_FUZZY_DISTANCE, distance
+            )));
         }
 
-        return Ok(results);
+        return Ok(self.expand_words(query, &|query, words| {
+            let mut exact_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            let mut fuzzy_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            for word in words {
+                if word.chars().count() < FUZZY_MIN_WORD_LENGTH {
+                    continue;
+                }
+                for field in self.default_fields() {
+                    let term = tantivy::Term::from_field_text(field, word);
+                    // an automaton with no edits matches the word exactly with a constant score.
+                    exact_queries.push((
+                        Occur::Should,
+                        Box::new(FuzzyTermQuery::new(term.clone(), 0, false)),
+                    ));
+                    fuzzy_queries.push((
+                        Occur::Should,
+                        Box::new(FuzzyTermQuery::new(term, distance, true)),
+                    ));
+                }
+            }
+            if fuzzy_queries.is_empty() {
+                return query;
+            }
+
+            return Box::new(BooleanQuery::new(vec![
+                (Occur::Should, query),
+                (
+                    Occur::Should,
+                    Box::new(BoostQuery::new(
+                        Box::new(BooleanQuery::new(exact_queries)),
+                        EXACT_MATCH_FLOOR,
+                    )),
+                ),
+                (
+                    Occur::Should,
+                    Box::new(BoostQuery::new(
+                        Box::new(BooleanQuery::new(fuzzy_queries)),
+                        FUZZY_BOOST,
+                    )),
+                ),
+            ]));
+        }));
+    }
+
+    /**
+     * Restricts a query to the documents within an access scope. The restrictions are unscored so that they don't change
+     * the ranking of the documents that remain.
+     */
+    fn with_scope(&self, query: Box<dyn Query>, scope: &AccessScope) -> IndexResult<Box<dyn Query>> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+        if let Some(paths) = &scope.paths {
+            let mut path_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            for path in paths {
+                let facet = path_facet(path)?;
+                if facet.is_root() {
+                    path_queries.push((Occur::Should, Box::new(AllQuery)));
+                    continue;
+                }
+                // the path facet is indexed with every ancestor directory so this matches everything under the path.
+                path_queries.push((
+                    Occur::Should,
+                    Box::new(TermQuery::new(
+                        tantivy::Term::from_facet(self.layout.field_path, &facet),
+                        IndexRecordOption::Basic,
+                    )),
+                ));
+            }
+            // an empty scope has no clauses and so matches nothing.
+            clauses.push((Occur::Must, unscored(BooleanQuery::new(path_queries))));
+        }
+        if let Some(unix_user) = &scope.unix_user {
+            let reader_queries: Vec<(Occur, Box<dyn Query>)> = unix_user
+                .readers()
+                .iter()
+                .map(|reader| {
+                    let term_query: Box<dyn Query> = Box::new(TermQuery::new(
+                        tantivy::Term::from_field_text(self.layout.field_readers, reader),
+                        IndexRecordOption::Basic,
+                    ));
+                    (Occur::Should, term_query)
+                })
+                .collect();
+            clauses.push((Occur::Must, unscored(BooleanQuery::new(reader_queries))));
+        }
+
+        if clauses.len() == 1 {
+            return Ok(clauses.pop().unwrap().1);
+        }
+        return Ok(Box::new(BooleanQuery::new(clauses)));
+    }
+
+    /**
+     * Extends a parsed query to also match documents whose filename contains a word starting with each word the query
+     * searches for, scoring those filename matches above matches elsewhere in the document.
+     */
+    fn with_filename_matches(&self, query: &dyn Query) -> Box<dyn Query> {
+        return self.expand_words(query, &|query, words| match self.filename_query(words) {
+            Some(filename_query) => Box::new(BooleanQuery::new(vec![
+                (Occur::Should, query),
+                (
+                    Occur::Should,
+                    Box::new(BoostQuery::new(filename_query, FILENAME_BOOST)),
+                ),
+            ])),
+            None => query,
+        });
+    }
+
+    /**
+     * Matches documents whose filename contains a word starting with each of the words, None when none of the words
+     * are long enough to match on.
+     */
+    fn filename_query(&self, words: &Vec<String>) -> Option<Box<dyn Query>> {
+        let filename_queries: Vec<(Occur, Box<dyn Query>)> = words
+            .iter()
+            .flat_map(|word| filename_words(word))
+            .filter(|word| word.chars().count() >= FILENAME_MIN_PREFIX)
+            .map(|word| {
+                // longer words were only indexed up to the maximum prefix length.
+                let prefix: String = word.chars().take(FILENAME_MAX_PREFIX).collect();
+                let term_query: Box<dyn Query> = Box::new(TermQuery::new(
+                    tantivy::Term::from_field_text(self.layout.field_filename, &prefix),
+                    IndexRecordOption::WithFreqs,
+                ));
+                (Occur::Must, term_query)
+            })
+            .collect();
+        if filename_queries.is_empty() {
+            return None;
+        }
+        return Some(Box::new(BooleanQuery::new(filename_queries)));
+    }
+
+    /**
+     * Fields searched by the words of a query that don't name a field.
+     */
+    fn default_fields(&self) -> Vec<tantivy::schema::Field> {
+        return vec![self.layout.field_title, self.layout.field_keyword];
     }
+
+    /**
+     * Rebuilds a parsed query with `expand` applied to the matches of each word or phrase that the query searches for
+     * in the default fields. Excluded words and words searched in a named field are left alone so that `-word`, `NOT`
+     * and `field:word` keep their meaning in the expanded query.
+     */
+    fn expand_words(
+        &self,
+        query: &dyn Query,
+        expand: &dyn Fn(Box<dyn Query>, &Vec<String>) -> Box<dyn Query>,
+    ) -> Box<dyn Query> {
+        if let Some(words) = self.default_field_words(query) {
+            return expand(query.box_clone(), &words);
+        }
+        return match query.downcast_ref::<BooleanQuery>() {
+            Some(boolean_query) => Box::new(BooleanQuery::new(
+                boolean_query
+                    .clauses()
+                    .iter()
+                    .map(|(occur, clause)| match occur {
+                        Occur::MustNot => (*occur, clause.box_clone()),
+                        _ => (*occur, self.expand_words(clause.as_ref(), expand)),
+                    })
+                    .collect(),
+            )),
+            None => query.box_clone(),
+        };
+    }
+
+    /**
+     * The words of the query the query parser builds for a word or phrase without a field name, one alternative per
+     * default field. None for any other query.
+     */
+    fn default_field_words(&self, query: &dyn Query) -> Option<Vec<String>> {
+        let mut fields: Vec<tantivy::schema::Field> = Vec::new();
+        let mut words: Option<Vec<String>> = None;
+        for (occur, clause) in query.downcast_ref::<BooleanQuery>()?.clauses() {
+            if *occur != Occur::Should {
+                return None;
+            }
+            let terms = if let Some(term_query) = clause.downcast_ref::<TermQuery>() {
+                vec![term_query.term().clone()]
+            } else if let Some(phrase_query) = clause.downcast_ref::<PhraseQuery>() {
+                phrase_query.phrase_terms()
+            } else {
+                return None;
+            };
+            let clause_words: Vec<String> =
+                terms.iter().map(|term| term.text().to_string()).collect();
+            if words.as_ref().map_or(false, |words| *words != clause_words) {
+                return None;
+            }
+            fields.push(terms.first()?.field());
+            words = Some(clause_words);
+        }
+        if fields != self.default_fields() {
+            return None;
+        }
+        return words;
+    }
+
+    /**
+     * Runs the query collecting the total hit count and facet counts alongside the given top docs collector. Directories
+     * are counted directly within each of the facet roots. Fails with `IndexError::Cancelled` if the cancellation flag
+     * is set while the query runs.
+     */
+    fn collect<C: Collector>(
+        &self,
+        searcher: &Searcher,
+        query: &dyn Query,
+        facet_roots: &Vec<tantivy::schema::Facet>,
+        cancelled: &Option<Arc<AtomicBool>>,
+        top_docs_collector: C,
+    ) -> IndexResult<(usize, C::Fruit, FacetCounts, FacetCounts)> {
+        let mut directories_collector = FacetCollector::for_field(self.layout.field_path);
+        for facet_root in facet_roots {
+            directories_collector.add_facet(facet_root.clone());
+        }
+        let mut file_types_collector = FacetCollector::for_field(self.layout.field_file_type);
+        file_types_collector.add_facet(tantivy::schema::Facet::root());
+
+        let collector = CancellableCollector {
+            collector: (
+                Count,
+                top_docs_collector,
+                directories_collector,
+                file_types_collector,
+            ),
+            cancelled: cancelled.clone(),
+        };
+        return match searcher.search(query, &collector) {
+            Ok(fruit) => Ok(fruit),
+            Err(_) if collector.is_cancelled() => Err(IndexError::Cancelled),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    /**
+     * Flattens the counts for the directories directly within each facet root, highest count first. The path facet
+     * also counts the files directly within a root, those are left out.
+     */
+    fn directory_facet_counts(
+        &self,
+        counts: &FacetCounts,
+        facet_roots: &Vec<tantivy::schema::Facet>,
+    ) -> IndexResult<Vec<FacetCount>> {
+        let mut children: Vec<(&tantivy::schema::Facet, u64)> = facet_roots
+            .iter()
+            .flat_map(|facet_root| counts.get(facet_root.clone()))
+            .collect();
+        children.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+        let mut directories: Vec<FacetCount> = Vec::new();
+        for (facet, count) in children {
+            if directories.len() == MAX_FACET_COUNTS {
+                break;
+            }
+            let path = facet.to_path_string();
+            // a file counts a single document, only those children can be files.
+            if count == 1 && self.metadata.get(Table::IndexedFiles, &path)?.is_some() {
+                continue;
+            }
+            directories.push(FacetCount {
+                value: path,
+                count: count,
+            });
+        }
+        return Ok(directories);
+    }
+}
+
+/**
+ * Sort key for a hit when ordering results by one of the fast fields. Hits that tie on the sort value are ordered
+ * by relevance.
+ */
+#[derive(Clone, PartialEq)]
+struct SortKey {
+    value: SortValue,
+    ascending: bool,
+    score: Score,
+}
+
+#[derive(Clone, PartialEq, PartialOrd)]
+enum SortValue {
+    Relevance(Score),
+    Number(u64),
+    Text(Vec<u8>),
+}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<std::cmp::Ordering> {
+        let mut ordering = self.value.partial_cmp(&other.value)?;
+        if self.ascending {
+            ordering = ordering.reverse();
+        }
+        return Some(ordering.then(self.score.partial_cmp(&other.score)?));
+    }
+}
+
+/**
+ * Score tweaker replacing the relevance score of each hit with the sort key for the requested sort order.
+ */
+struct FastFieldSort {
+    sort: SortOrder,
+    field_size: tantivy::schema::Field,
+    field_modified: tantivy::schema::Field,
+    field_name: tantivy::schema::Field,
+}
+
+enum SegmentSortValues {
+    Relevance,
+    Number(DynamicFastFieldReader<u64>),
+    Text(BytesFastFieldReader),
+}
+
+struct SegmentFastFieldSort {
+    values: SegmentSortValues,
+    ascending: bool,
+}
+
+impl ScoreTweaker<SortKey> for FastFieldSort {
+    type Child = SegmentFastFieldSort;
+
+    fn segment_tweaker(&self, segment_reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+        let fast_fields = segment_reader.fast_fields();
+        let values = match self.sort.field {
+            SortField::Relevance => SegmentSortValues::Relevance,
+            SortField::Size => SegmentSortValues::Number(fast_fields.u64(self.field_size)?),
+            SortField::Modified => SegmentSortValues::Number(fast_fields.u64(self.field_modified)?),
+            SortField::Name => SegmentSortValues::Text(fast_fields.bytes(self.field_name)?),
+        };
+        return Ok(SegmentFastFieldSort {
+            values: values,
+            ascending: self.sort.direction == SortDirection::Ascending,
+        });
+    }
+}
+
+impl ScoreSegmentTweaker<SortKey> for SegmentFastFieldSort {
+    fn score(&mut self, doc: DocId, score: Score) -> SortKey {
+        let value = match &self.values {
+            SegmentSortValues::Relevance => SortValue::Relevance(score),
+            SegmentSortValues::Number(reader) => SortValue::Number(reader.get(doc)),
+            SegmentSortValues::Text(reader) => SortValue::Text(reader.get_bytes(doc).to_vec()),
+        };
+        return SortKey {
+            value: value,
+            ascending: self.ascending,
+            score: score,
+        };
+    }
+}
+
+/**
+ * Collector that stops a search once its cancellation flag is set, checking the flag before each segment and every
+ * `CANCELLATION_CHECK_INTERVAL` documents within one. Without a flag it collects exactly like the wrapped collector.
+ */
+struct CancellableCollector<C: Collector> {
+    collector: C,
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl<C: Collector> CancellableCollector<C> {
+    fn is_cancelled(&self) -> bool {
+        return self
+            .cancelled
+            .as_ref()
+            .map_or(false, |cancelled| cancelled.load(AtomicOrdering::SeqCst));
+    }
+}
+
+impl<C: Collector> Collector for CancellableCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = C::Child;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<C::Child> {
+        return self.collector.for_segment(segment_local_id, segment);
+    }
+
+    fn requires_scoring(&self) -> bool {
+        return self.collector.requires_scoring();
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<C::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<C::Fruit> {
+        return self.collector.merge_fruits(segment_fruits);
+    }
+
+    fn collect_segment(
+        &self,
+        weight: &dyn Weight,
+        segment_ord: u32,
+        reader: &SegmentReader,
+    ) -> tantivy::Result<<C::Child as SegmentCollector>::Fruit> {
+        if self.cancelled.is_none() {
+            return self.collector.collect_segment(weight, segment_ord, reader);
+        }
+
+        // the same loop as the default implementation, broken off once the search is cancelled.
+        let mut segment_collector = self.for_segment(segment_ord, reader)?;
+        let mut scorer = weight.scorer(reader, 1.0)?;
+        let delete_bitset = reader.delete_bitset();
+        let mut doc = scorer.doc();
+        let mut visited: usize = 0;
+        while doc != TERMINATED {
+            if visited % CANCELLATION_CHECK_INTERVAL == 0 && self.is_cancelled() {
+                return Err(TantivyError::SystemError(String::from("search cancelled")));
+            }
+            visited += 1;
+            if delete_bitset.map_or(true, |bitset| bitset.is_alive(doc)) {
+                segment_collector.collect(doc, scorer.score());
+            }
+            doc = scorer.advance();
+        }
+        return Ok(segment_collector.harvest());
+    }
+}
+
+/**
+ * Splits a filename (or query) into lowercased words on punctuation, whitespace and camelCase boundaries, e.g.
+ * "QuarterlyReport_Q3.xlsx" becomes ["quarterly", "report", "q3", "xlsx"].
+ */
+fn filename_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut previous_lowercase = false;
+    for c in text.chars() {
+        if !c.is_alphanumeric() || (previous_lowercase && c.is_uppercase()) {
+            if !word.is_empty() {
+                words.push(word.to_lowercase());
+                word.clear();
+            }
+        }
+        if c.is_alphanumeric() {
+            word.push(c);
+        }
+        previous_lowercase = c.is_lowercase();
+    }
+    if !word.is_empty() {
+        words.push(word.to_lowercase());
+    }
+    return words;
+}
+
+/**
+ * Facet for a directory or file path, the root facet for `/`.
+ */
+fn path_facet(path: &Path) -> IndexResult<tantivy::schema::Facet> {
+    let path = path.to_string_lossy();
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return Ok(tantivy::schema::Facet::root());
+    }
+    return Ok(tantivy::schema::Facet::from_text(path)?);
+}
+
+/**
+ * Facets for the directories counted by the directory facet. A root within another root is dropped since tantivy can't
+ * count a facet alongside its ancestor, its documents are counted under the outer root instead.
+ */
+fn facet_roots(roots: &Vec<PathBuf>) -> IndexResult<Vec<tantivy::schema::Facet>> {
+    let mut facet_roots: Vec<tantivy::schema::Facet> = Vec::new();
+    let mut roots = roots.clone();
+    // outer roots sort before the roots within them.
+    roots.sort();
+    for root in roots {
+        let facet = path_facet(&root)?;
+        if !facet_roots.iter().any(|facet_root| {
+            facet_root.is_root() || facet_root.is_prefix_of(&facet) || *facet_root == facet
+        }) {
+            facet_roots.push(facet);
+        }
+    }
+    if facet_roots.is_empty() {
+        facet_roots.push(tantivy::schema::Facet::root());
+    }
+    return Ok(facet_roots);
+}
+
+/**
+ * Wraps a query that only filters documents so that it doesn't contribute to their relevance.
+ */
+fn unscored(query: BooleanQuery) -> Box<dyn Query> {
+    return Box::new(BoostQuery::new(Box::new(query), 0.0));
+}
+
+/**
+ * Orders counts keyed by value highest count first, keeping at most `MAX_FACET_COUNTS` of them.
+ */
+fn largest_counts(counts: HashMap<String, u64>) -> Vec<FacetCount> {
+    let mut counts: Vec<FacetCount> = counts
+        .into_iter()
+        .map(|(value, count)| FacetCount {
+            value: value,
+            count: count,
+        })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(MAX_FACET_COUNTS);
+    return counts;
+}
+
+/**
+ * Total size of the files under a directory.
+ */
+pub fn dir_size(dir: &Path) -> IndexResult<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    return Ok(size);
+}
+
+/**
+ * Flattens the counts for the direct children of the root facet, highest count first, each named by its last segment.
+ */
+fn top_level_facet_counts(counts: &FacetCounts) -> Vec<FacetCount> {
+    return counts
+        .top_k(tantivy::schema::Facet::root(), MAX_FACET_COUNTS)
+        .into_iter()
+        .map(|(facet, count)| FacetCount {
+            value: facet.to_path().last().unwrap_or(&"").to_string(),
+            count: count,
+        })
+        .collect();
 }
 
 /**
@@ -164,83 +1741,659 @@ impl SearchableIndex for TantivyIndex {
 struct TantivyIndexWriter<'a> {
     index: &'a TantivyIndex,
     tantivy_writer: tantivy::IndexWriter,
-    indexed_files_txn: Option<Arc<lmdb::WriteTransaction<'static>>>,
+    metadata_txn: Option<Box<dyn MetadataWriteTransaction>>,
+    generation: u64, // generation this writer's commit will be stamped with.
+    added_paths: Vec<String>,
 }
 
 impl TantivyIndexWriter<'_> {
-    fn create(index: &mut TantivyIndex) -> Result<TantivyIndexWriter> {
+    fn create(index: &TantivyIndex) -> IndexResult<TantivyIndexWriter> {
+        let (tantivy_writer, metadata_txn, generation) = index.begin_commit()?;
         return Ok(TantivyIndexWriter {
             index: index,
-            tantivy_writer: index.index.writer(50_000_000 /* 50 MB heap size */)?,
-            indexed_files_txn: Some(Arc::new(lmdb::WriteTransaction::new(
-                index.lmdb_env.clone(),
-            )?)),
+            tantivy_writer: tantivy_writer,
+            metadata_txn: Some(metadata_txn),
+            generation: generation,
+            added_paths: Vec::new(),
         });
     }
 }
 
+impl TantivyIndexWriter<'_> {
+    fn metadata_txn(&mut self) -> IndexResult<&mut Box<dyn MetadataWriteTransaction>> {
+        return self.metadata_txn.as_mut().ok_or(IndexError::WriterClosed);
+    }
+}
+
 impl IndexWriter for TantivyIndexWriter<'_> {
-    fn should_add_document(&mut self, path: &Path) -> bool {
-        let reader = self
-            .indexed_files_txn
-            .as_ref()
-            .expect("IndexWriter used after commit")
-            .access();
+    fn should_add_document(&mut self, path: &Path) -> IndexResult<bool> {
+        let path = path.to_string_lossy();
+        let txn = self.metadata_txn()?;
+        let doc = txn.get(Table::IndexedFiles, &path)?;
+        return Ok(doc.is_none() || txn.get(Table::Unextracted, &path)?.is_some());
+    }
 
-        let doc: Option<&str> = reader
-            .get(
-                &(&self.index.db_indexed_files),
-                path.to_string_lossy().as_bytes(),
-            )
-            .to_opt()
+    fn add_document(&mut self, doc: &Document, keywords: &Vec<String>) -> IndexResult<()> {
+        let path = doc.metadata.path.to_string_lossy();
+        let tantivy_doc = self.index.build_document(doc, keywords, self.generation)?;
+        let store_content = self.index.store_content;
+
+        // insert the full document in the metadata store for later retrieval
+        let txn = self.metadata_txn()?;
+        txn.put(
+            Table::IndexedFiles,
+            &path,
+            serde_json::to_string(&doc)?.as_bytes(),
+        )?;
+        let unextracted = txn.get(Table::Unextracted, &path)?.is_some();
+        if unextracted {
+            txn.delete(Table::Unextracted, &path)?;
+        }
+        // documents without keywords are stored too so a rebuild can tell they have no content from never stored.
+        if store_content {
+            txn.put(
+                Table::Content,
+                &path,
+                &lz4_flex::compress_prepend_size(&serde_json::to_vec(keywords)?),
+            )?;
+        }
+
+        if unextracted {
+            // replaces the document rebuilt from its metadata alone.
+            self.tantivy_writer.delete_term(tantivy::Term::from_facet(
+                self.index.layout.field_path,
+                &tantivy::schema::Facet::from_text(&path)?,
+            ));
+        }
+        self.tantivy_writer.add_document(tantivy_doc);
+        self.added_paths.push(path.to_string());
+
+        return Ok(());
+    }
+
+    fn record_indexing_pass(&mut self, pass: &IndexingPass) -> IndexResult<()> {
+        let pass = serde_json::to_string(pass)?;
+        self.metadata_txn()?
+            .put(Table::Meta, LAST_INDEXING_PASS_KEY, pass.as_bytes())?;
+        return Ok(());
+    }
+
+    fn commit(&mut self) -> IndexResult<()> {
+        let metadata_txn = self.metadata_txn.take().ok_or(IndexError::WriterClosed)?;
+        return self.index.commit_generation(
+            &mut self.tantivy_writer,
+            metadata_txn,
+            self.generation,
+            &self.added_paths,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::testing::{document, TempDir};
+
+    fn add_committed(index: &TantivyIndex, path: &str) {
+        let mut writer = index.begin_add_documents().unwrap();
+        writer
+            .add_document(&document(path), &vec![String::from("committed")])
             .unwrap();
-        return !doc.is_some();
+        writer.commit().unwrap();
+    }
+
+    /**
+     * Checks both stores hold the same documents at the same generation.
+     */
+    fn assert_consistent(index: &TantivyIndex, generation: u64, documents: usize) {
+        assert_eq!(index.tantivy_generation().unwrap(), generation);
+        assert_eq!(index.metadata_generation().unwrap(), generation);
+        let report = index.check(false).unwrap();
+        assert_eq!(report.tantivy_documents, documents);
+        assert_eq!(report.metadata_documents, documents);
+        assert!(report.missing_metadata.is_empty());
+        assert!(report.missing_from_index.is_empty());
+    }
+
+    #[test]
+    fn reopening_rolls_back_tantivy_commit_without_metadata() {
+        let dir = TempDir::new("tantivy-ahead");
+        {
+            let index = TantivyIndex::create(&dir.path, MetadataBackend::Lmdb).unwrap();
+            add_committed(&index, "/data/kept.txt");
+
+            // commit tantivy and drop the metadata transaction, as if the process died between the two.
+            let mut writer = TantivyIndexWriter::create(&index).unwrap();
+            writer
+                .add_document(&document("/data/lost.txt"), &Vec::new())
+                .unwrap();
+            commit_tantivy(&mut writer.tantivy_writer, writer.generation).unwrap();
+            assert_eq!(index.tantivy_generation().unwrap(), 2);
+            assert_eq!(index.metadata_generation().unwrap(), 1);
+        }
+
+        let index = TantivyIndex::create(&dir.path, MetadataBackend::Lmdb).unwrap();
+        assert_consistent(&index, 1, 1);
+    }
+
+    #[test]
+    fn next_writer_rolls_back_tantivy_commit_without_metadata() {
+        let index = TantivyIndex::create_in_memory().unwrap();
+        add_committed(&index, "/data/kept.txt");
+        {
+            // the metadata commit failed after tantivy's and the process carries on.
+            let mut writer = TantivyIndexWriter::create(&index).unwrap();
+            writer
+                .add_document(&document("/data/lost.txt"), &Vec::new())
+                .unwrap();
+            commit_tantivy(&mut writer.tantivy_writer, writer.generation).unwrap();
+        }
+
+        add_committed(&index, "/data/added.txt");
+        assert_consistent(&index, 2, 2);
+        assert!(index
+            .get_document(Path::new("/data/lost.txt"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reopening_rolls_back_metadata_commit_without_tantivy() {
+        let dir = TempDir::new("metadata-ahead");
+        {
+            let mut index = TantivyIndex::create(&dir.path, MetadataBackend::Lmdb).unwrap();
+            index.set_store_content(true);
+            add_committed(&index, "/data/kept.txt");
+
+            // commit the metadata store but not tantivy, as if tantivy's commit was lost in a crash.
+            let mut writer = TantivyIndexWriter::create(&index).unwrap();
+            writer
+                .add_document(&document("/data/lost.txt"), &vec![String::from("lost")])
+                .unwrap();
+            let mut txn = writer.metadata_txn.take().unwrap();
+            stamp_generation(txn.as_mut(), writer.generation, &writer.added_paths).unwrap();
+            txn.commit().unwrap();
+            assert_eq!(index.tantivy_generation().unwrap(), 1);
+            assert_eq!(index.metadata_generation().unwrap(), 2);
+        }
+
+        let index = TantivyIndex::create(&dir.path, MetadataBackend::Lmdb).unwrap();
+        assert_consistent(&index, 1, 1);
+        let lost = Path::new("/data/lost.txt");
+        assert!(index.get_document(lost).unwrap().is_none());
+        assert!(index.read_content("/data/lost.txt").unwrap().is_none());
+        assert!(index.metadata.get(Table::Journal, "2").unwrap().is_none());
+        assert_eq!(
+            index.read_content("/data/kept.txt").unwrap(),
+            Some(vec![String::from("committed")])
+        );
     }
 
-    fn add_document(&mut self, doc: &Document, keywords: &Vec<String>) -> Result<()> {
-        // insert the full document in leveldb for later retrieval
+    #[test]
+    fn rebuilding_marks_documents_without_stored_content_for_reextraction() {
+        let mut index = TantivyIndex::create_in_memory().unwrap();
+        add_committed(&index, "/data/unstored.txt");
+        index.set_store_content(true);
+        add_committed(&index, "/data/stored.txt");
+
+        assert!(matches!(
+            index.ensure_content_stored(),
+            Err(IndexError::ContentNotStored(1))
+        ));
+        index.rebuild_from_metadata().unwrap();
+        assert_consistent(&index, 2, 2);
+        assert_eq!(
+            search_paths(&index, "committed", &SearchOptions::default()),
+            vec!["/data/stored.txt"]
+        );
+        assert_eq!(
+            search_paths(&index, "unstored", &SearchOptions::default()),
+            vec!["/data/unstored.txt"]
+        );
+
+        // the next indexing pass re-extracts the unstored document, replacing the one rebuilt from its metadata.
         {
-            let mut access = self
-                .indexed_files_txn
-                .as_ref()
-                .expect("IndexWriter used after commit")
-                .access();
-            access
-                .put(
-                    &(&self.index.db_indexed_files),
-                    doc.metadata.path.to_string_lossy().as_bytes(),
-                    serde_json::to_string(&doc)?.as_bytes(),
-                    lmdb::put::Flags::empty(),
+            let mut writer = index.begin_add_documents().unwrap();
+            assert!(writer
+                .should_add_document(Path::new("/data/unstored.txt"))
+                .unwrap());
+            assert!(!writer
+                .should_add_document(Path::new("/data/stored.txt"))
+                .unwrap());
+            writer
+                .add_document(
+                    &document("/data/unstored.txt"),
+                    &vec![String::from("committed")],
                 )
                 .unwrap();
+            assert!(!writer
+                .should_add_document(Path::new("/data/unstored.txt"))
+                .unwrap());
+            writer.commit().unwrap();
         }
+        assert_consistent(&index, 3, 2);
+        assert!(index.metadata.is_empty(Table::Unextracted).unwrap());
+        index.ensure_content_stored().unwrap();
+        assert_eq!(
+            search_paths(&index, "committed", &SearchOptions::default()).len(),
+            2
+        );
+    }
 
-        // create the tantivy document to insert
-        let path = doc.metadata.path.to_string_lossy();
-        let mut tantivy_doc = tantivy::doc! {
-            self.index.layout.field_title => doc.title.clone()
+    /**
+     * In-memory index of three documents with distinct sizes, modification times and permissions.
+     */
+    fn sample_index() -> TantivyIndex {
+        let index = TantivyIndex::create_in_memory().unwrap();
+        {
+            let mut writer = index.begin_add_documents().unwrap();
+            for (path, size, modified, mode, keywords) in [
+                ("/data/docs/alpha.txt", 300, 10, 0o644, "quarterly report"),
+                ("/data/docs/beta.md", 100, 30, 0o600, "quarterly budget"),
+                ("/data/media/gamma.txt", 200, 20, 0o640, "holiday report"),
+            ] {
+                let mut doc = document(path);
+                doc.metadata.size = size;
+                doc.metadata.modified = modified;
+                doc.metadata.permissions = Some(UnixPermissions {
+                    uid: 1000,
+                    gid: 100,
+                    mode: mode,
+                });
+                writer
+                    .add_document(&doc, &vec![String::from(keywords)])
+                    .unwrap();
+            }
+            writer.commit().unwrap();
+        }
+        return index;
+    }
+
+    fn search_paths(index: &TantivyIndex, query: &str, options: &SearchOptions) -> Vec<String> {
+        return search_paths_limit(index, query, options, 10);
+    }
+
+    fn search_paths_limit(
+        index: &TantivyIndex,
+        query: &str,
+        options: &SearchOptions,
+        limit: usize,
+    ) -> Vec<String> {
+        return index
+            .search(query, limit, 0, options)
+            .unwrap()
+            .hits
+            .iter()
+            .map(|hit| hit.document.metadata.path.to_string_lossy().to_string())
+            .collect();
+    }
+
+    fn facet_counts(counts: &Vec<FacetCount>) -> Vec<(&str, u64)> {
+        return counts
+            .iter()
+            .map(|count| (count.value.as_str(), count.count))
+            .collect();
+    }
+
+    fn browse_names(
+        index: &TantivyIndex,
+        path: &str,
+        limit: usize,
+        offset: usize,
+        sort: SortOrder,
+    ) -> Vec<String> {
+        return index
+            .browse(
+                Path::new(path),
+                limit,
+                offset,
+                sort,
+                &AccessScope::default(),
+            )
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+    }
+
+    #[test]
+    fn browse_lists_directories_before_files_in_order() {
+        let index = TantivyIndex::create_in_memory().unwrap();
+        {
+            let mut writer = index.begin_add_documents().unwrap();
+            for (path, size) in [
+                ("/b/zeta.txt", 1),
+                ("/b/Alpha.txt", 3),
+                ("/b/mid.txt", 2),
+                ("/b/sub/x.txt", 5),
+                ("/b/sub/y.txt", 5),
+                ("/other/z.txt", 1),
+            ] {
+                let mut doc = document(path);
+                doc.metadata.size = size;
+                writer.add_document(&doc, &Vec::new()).unwrap();
+            }
+            writer.commit().unwrap();
+        }
+        let order = |field: SortField, direction: SortDirection| {
+            return SortOrder {
+                field: field,
+                direction: direction,
+            };
         };
-        tantivy_doc.add_facet(
-            self.index.layout.field_path,
-            tantivy::schema::Facet::from(&path),
+        let by_name = order(SortField::Name, SortDirection::Ascending);
+
+        let listing = index
+            .browse(Path::new("/b"), 10, 0, by_name, &AccessScope::default())
+            .unwrap();
+        assert_eq!(listing.total_entries, 4);
+        let sub = &listing.entries[0];
+        assert_eq!(sub.name, "sub");
+        assert_eq!(sub.kind, EntryKind::Directory);
+        assert_eq!(sub.documents, 2);
+        assert!(sub.id.is_none());
+        let alpha = &listing.entries[1];
+        assert_eq!(alpha.kind, EntryKind::File);
+        assert_eq!(alpha.path, PathBuf::from("/b/Alpha.txt"));
+        assert_eq!(alpha.size, Some(3));
+        assert!(alpha.id.is_some());
+
+        assert_eq!(
+            browse_names(&index, "/b", 10, 0, by_name),
+            vec!["sub", "Alpha.txt", "mid.txt", "zeta.txt"]
+        );
+        assert_eq!(
+            browse_names(
+                &index,
+                "/b/",
+                10,
+                0,
+                order(SortField::Size, SortDirection::Descending)
+            ),
+            vec!["sub", "Alpha.txt", "mid.txt", "zeta.txt"]
+        );
+        assert_eq!(
+            browse_names(
+                &index,
+                "/b",
+                10,
+                0,
+                order(SortField::Size, SortDirection::Ascending)
+            ),
+            vec!["sub", "zeta.txt", "mid.txt", "Alpha.txt"]
+        );
+        assert_eq!(
+            browse_names(&index, "/b", 2, 1, by_name),
+            vec!["Alpha.txt", "mid.txt"]
+        );
+        assert_eq!(
+            browse_names(&index, "/b", 10, 4, by_name),
+            Vec::<String>::new()
         );
+        assert_eq!(
+            browse_names(&index, "/", 10, 0, by_name),
+            vec!["b", "other"]
+        );
+        assert_eq!(
+            browse_names(&index, "/missing", 10, 0, by_name),
+            Vec::<String>::new()
+        );
+        assert!(matches!(
+            index.browse(
+                Path::new("/b"),
+                10,
+                0,
+                order(SortField::Relevance, SortDirection::Descending),
+                &AccessScope::default()
+            ),
+            Err(IndexError::InvalidOption(_))
+        ));
 
-        for keyword in keywords {
-            tantivy_doc.add_text(self.index.layout.field_keyword, keyword);
+        // an entry whose metadata can't be read is left out of the listing.
+        let mut txn = index.metadata.begin_write().unwrap();
+        txn.put(Table::IndexedFiles, "/b/mid.txt", b"{").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(
+            browse_names(&index, "/b", 10, 0, by_name),
+            vec!["sub", "Alpha.txt", "zeta.txt"]
+        );
+    }
+
+    #[test]
+    fn stats_skip_documents_with_invalid_metadata() {
+        let index = sample_index();
+        let mut txn = index.metadata.begin_write().unwrap();
+        txn.put(Table::IndexedFiles, "/data/docs/corrupt.txt", b"{")
+            .unwrap();
+        txn.commit().unwrap();
+
+        let stats = index.stats(&vec![PathBuf::from("/data/docs")]).unwrap();
+        assert_eq!(stats.invalid_documents, 1);
+        assert_eq!(stats.roots[0].documents, 2);
+        assert_eq!(stats.bytes_indexed, 600);
+    }
+
+    #[test]
+    fn search_reports_total_hits_and_facets() {
+        let index = sample_index();
+        let mut options = SearchOptions::default();
+        options.facet_roots = vec![PathBuf::from("/data")];
+
+        let results = index.search("report", 1, 0, &options).unwrap();
+        assert_eq!(results.total_hits, 2);
+        assert_eq!(results.hits.len(), 1);
+        assert!(results.hits[0].score > 0.0);
+        assert_eq!(
+            facet_counts(&results.directories),
+            vec![("/data/docs", 1), ("/data/media", 1)]
+        );
+        assert_eq!(facet_counts(&results.file_types), vec![("txt", 2)]);
+
+        assert!(matches!(
+            index.search("report", 0, 0, &options),
+            Err(IndexError::InvalidOption(_))
+        ));
+    }
+
+    #[test]
+    fn search_sorts_by_fast_fields() {
+        let index = sample_index();
+        let mut options = SearchOptions::default();
+        let sorted = |field, direction, options: &mut SearchOptions| {
+            options.sort = SortOrder {
+                field: field,
+                direction: direction,
+            };
+            return search_paths(&index, "quarterly OR report", options);
+        };
+
+        assert_eq!(
+            sorted(SortField::Size, SortDirection::Ascending, &mut options),
+            vec![
+                "/data/docs/beta.md",
+                "/data/media/gamma.txt",
+                "/data/docs/alpha.txt"
+            ]
+        );
+        assert_eq!(
+            sorted(SortField::Modified, SortDirection::Descending, &mut options),
+            vec![
+                "/data/docs/beta.md",
+                "/data/media/gamma.txt",
+                "/data/docs/alpha.txt"
+            ]
+        );
+        assert_eq!(
+            sorted(SortField::Name, SortDirection::Ascending, &mut options),
+            vec![
+                "/data/docs/alpha.txt",
+                "/data/docs/beta.md",
+                "/data/media/gamma.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn fuzzy_search_matches_typos_below_exact_matches() {
+        let index = sample_index();
+        let mut options = SearchOptions::default();
+        assert!(search_paths(&index, "quartrly", &options).is_empty());
+
+        options.fuzzy_distance = Some(1);
+        assert_eq!(search_paths(&index, "quartrly", &options).len(), 2);
+        let results = index.search("holiday repot", 10, 0, &options).unwrap();
+        assert_eq!(
+            results.hits[0].document.metadata.path,
+            PathBuf::from("/data/media/gamma.txt")
+        );
+        assert_eq!(results.hits.len(), 2);
+        assert!(results.hits[0].score > results.hits[1].score);
+
+        options.fuzzy_distance = Some(MAX_FUZZY_DISTANCE + 1);
+        assert!(matches!(
+            index.search("quartrly", 10, 0, &options),
+            Err(IndexError::InvalidOption(_))
+        ));
+    }
+
+    fn add_documents(index: &TantivyIndex, documents: &[(&str, &str)]) {
+        let mut writer = index.begin_add_documents().unwrap();
+        for (path, keywords) in documents {
+            writer
+                .add_document(&document(path), &vec![String::from(*keywords)])
+                .unwrap();
         }
-        self.tantivy_writer.add_document(tantivy_doc);
+        writer.commit().unwrap();
+    }
 
-        return Ok(());
+    #[test]
+    fn filename_matches_partial_words_within_the_parsed_query() {
+        let index = TantivyIndex::create_in_memory().unwrap();
+        add_documents(
+            &index,
+            &[
+                ("/d/Quarterly_Report_Q3.xlsx", "revenue numbers"),
+                ("/d/summary.txt", "quarterly numbers"),
+            ],
+        );
+        let options = SearchOptions::default();
+
+        assert_eq!(
+            search_paths(&index, "quarterl", &options),
+            vec!["/d/Quarterly_Report_Q3.xlsx"]
+        );
+        assert_eq!(
+            search_paths(&index, "quarterly", &options),
+            vec!["/d/Quarterly_Report_Q3.xlsx", "/d/summary.txt"]
+        );
+        // excluded words and words scoped to a field aren't matched against filenames.
+        for query in [
+            "quarterly -report",
+            "quarterly AND NOT report",
+            "numbers -report",
+        ] {
+            let paths = search_paths(&index, query, &options);
+            assert!(
+                !paths.contains(&String::from("/d/Quarterly_Report_Q3.xlsx")),
+                "{}",
+                query
+            );
+        }
+        assert_eq!(
+            search_paths(&index, "numbers -report", &options),
+            vec!["/d/summary.txt"]
+        );
+        assert!(search_paths(&index, "title:quarterl", &options).is_empty());
+
+        let mut options = SearchOptions::default();
+        options.filenames_only = true;
+        assert_eq!(
+            search_paths(&index, "quarterl", &options),
+            vec!["/d/Quarterly_Report_Q3.xlsx"]
+        );
+        assert!(search_paths(&index, "quarterl -report", &options).is_empty());
     }
 
-    fn commit(&mut self) -> Result<()> {
-        self.tantivy_writer.commit()?;
+    #[test]
+    fn exact_matches_outrank_typos_however_common_the_word() {
+        let index = TantivyIndex::create_in_memory().unwrap();
+        let long_text = format!("{} receipt", vec!["filler"; 500].join(" "));
+        let mut documents: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("/r/common{}.txt", i), String::from("receipt")))
+            .collect();
+        documents.push((String::from("/r/long.txt"), long_text));
+        // the typo is in both the title and the keywords, adding a fuzzy match for each.
+        documents.push((String::from("/r/recipt.txt"), String::from("recipt lunch")));
+        let documents: Vec<(&str, &str)> = documents
+            .iter()
+            .map(|(path, keywords)| (path.as_str(), keywords.as_str()))
+            .collect();
+        add_documents(&index, &documents);
 
-        // we clear out the IndexWriter's handle on the WriteTransaction and then unwrap and commit the only remaining handle.
-        let indexed_files_txn = self.indexed_files_txn.as_ref().unwrap().clone();
-        self.indexed_files_txn = None;
+        let mut options = SearchOptions::default();
+        options.fuzzy_distance = Some(1);
+        let paths = search_paths_limit(&index, "receipt", &options, 30);
+        assert_eq!(paths.len(), 22);
+        assert_eq!(paths.last().unwrap(), "/r/recipt.txt");
 
-        Arc::try_unwrap(indexed_files_txn).unwrap().commit()?;
-        return Ok(());
+        // excluded words aren't expanded with typos.
+        let paths = search_paths_limit(&index, "receipt -lunch", &options, 30);
+        assert_eq!(paths.len(), 21);
+        assert!(!paths.contains(&String::from("/r/recipt.txt")));
+    }
+
+    #[test]
+    fn cancelled_search_fails() {
+        let index = sample_index();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut options = SearchOptions::default();
+        options.cancelled = Some(cancelled.clone());
+        assert_eq!(
+            search_paths(&index, "holiday", &options),
+            vec!["/data/media/gamma.txt"]
+        );
+
+        cancelled.store(true, AtomicOrdering::SeqCst);
+        assert!(matches!(
+            index.search("holiday", 10, 0, &options),
+            Err(IndexError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn scope_limits_hits_and_counts() {
+        let index = sample_index();
+        let mut options = SearchOptions::default();
+        options.scope.paths = Some(vec![PathBuf::from("/data/docs")]);
+        let results = index.search("report", 10, 0, &options).unwrap();
+        assert_eq!(results.total_hits, 1);
+        assert_eq!(
+            results.hits[0].document.metadata.path,
+            PathBuf::from("/data/docs/alpha.txt")
+        );
+
+        // another user only sees world readable files, a member of the group also sees group readable ones.
+        options.scope.paths = None;
+        options.scope.unix_user = Some(UnixUser {
+            uid: Some(2000),
+            gids: Vec::new(),
+        });
+        assert_eq!(
+            search_paths(&index, "quarterly OR report", &options),
+            vec!["/data/docs/alpha.txt"]
+        );
+        options.scope.unix_user = Some(UnixUser {
+            uid: Some(2000),
+            gids: vec![100],
+        });
+        options.sort = SortOrder {
+            field: SortField::Name,
+            direction: SortDirection::Ascending,
+        };
+        assert_eq!(
+            search_paths(&index, "quarterly OR report", &options),
+            vec!["/data/docs/alpha.txt", "/data/media/gamma.txt"]
+        );
     }
 }
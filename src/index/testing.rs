@@ -0,0 +1,45 @@
+use crate::index::{Document, DocumentMetadata};
+use std::fs;
+use std::path::PathBuf;
+
+/**
+ * Empty directory for files used by a test, removed again when dropped.
+ */
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path =
+            std::env::temp_dir().join(format!("guidebook-test-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        return TempDir { path: path };
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/**
+ * Document for a file that needn't exist, titled with its path.
+ */
+pub fn document(path: &str) -> Document {
+    return Document {
+        metadata: DocumentMetadata {
+            path: PathBuf::from(path),
+            size: 1,
+            modified: 0,
+            permissions: None,
+        },
+        title: String::from(path),
+        preview_text: None,
+        preview_img_path: None,
+    };
+}
@@ -1,15 +1,222 @@
 use std::{
     fs,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::index::{Document, IndexWriter};
+use crate::index::{Document, Index, IndexWriter, IndexingPass, WritableIndex};
 use anyhow::{Context, Result};
 use jwalk;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use self::metadata_providers::BasicAttributesMetadataProvider;
+
+lazy_static! {
+    // the latest indexing pass started in the background by this process.
+    static ref background_pass: Mutex<Option<Arc<IndexingMonitor>>> = Mutex::new(None);
+}
+
+/**
+ * Runs an indexing pass over the paths and commits it, reporting progress to the monitor if one is given. A cancelled
+ * pass commits the documents indexed so far but isn't recorded as the last indexing pass.
+ */
+pub fn run_indexing_pass(
+    paths: &Vec<PathBuf>,
+    index: &dyn WritableIndex,
+    monitor: Option<Arc<IndexingMonitor>>,
+) -> Result<IndexingPass> {
+    let mut writer = index
+        .begin_add_documents()
+        .context("failed to get a document writer")?;
+
+    let mut providers: Vec<Box<dyn MetadataProvider>> = Vec::new();
+    providers.push(Box::new(BasicAttributesMetadataProvider::new()));
+
+    let mut worker = IndexerWorker::create(paths, providers);
+    worker.monitor = monitor.clone();
+    let pass = worker.index(writer.as_mut())?;
+    let cancelled = monitor.map_or(false, |monitor| monitor.is_cancelled());
+    if !cancelled {
+        writer
+            .record_indexing_pass(&pass)
+            .context("failed to record the indexing pass")?;
+    }
+
+    writer
+        .commit()
+        .context("failed to commit newly indexed documents")?;
+    println!(
+        "indexing pass {} in {:.1}s: {} files scanned, {} indexed, {} skipped, {} errors",
+        if cancelled { "cancelled" } else { "finished" },
+        pass.duration_millis as f64 / 1000.0,
+        pass.scanned,
+        pass.indexed,
+        pass.skipped,
+        pass.errors
+    );
+    return Ok(pass);
+}
+
+/**
+ * Starts an indexing pass on a background thread, searches continue against the index while it runs. Returns None if
+ * a pass started by this process is still running.
+ */
+pub fn start_indexing_pass(
+    paths: Vec<PathBuf>,
+    index: Arc<dyn Index>,
+) -> Option<Arc<IndexingMonitor>> {
+    let mut current = background_pass.lock().unwrap();
+    if let Some(monitor) = current.as_ref() {
+        if !monitor.is_finished() {
+            return None;
+        }
+    }
+
+    // the previous pass visited roughly as many files as this one will, which gives an estimate of the time remaining.
+    let expected_files = match index.last_indexing_pass() {
+        Ok(pass) => pass.map(|pass| pass.scanned),
+        Err(_) => None,
+    };
+    let monitor = Arc::new(IndexingMonitor::new(expected_files));
+    current.replace(monitor.clone());
+
+    let thread_monitor = monitor.clone();
+    std::thread::spawn(move || {
+        match run_indexing_pass(&paths, index.as_writable(), Some(thread_monitor.clone())) {
+            Ok(pass) => thread_monitor.finish(Some(&pass), None),
+            Err(e) => {
+                println!("indexing pass failed: {:#}", e);
+                thread_monitor.finish(None, Some(format!("{:#}", e)));
+            }
+        }
+    });
+    return Some(monitor);
+}
+
+/**
+ * The latest indexing pass started in the background by this process, whether or not it is still running.
+ */
+pub fn background_indexing_pass() -> Option<Arc<IndexingMonitor>> {
+    return background_pass.lock().unwrap().clone();
+}
+
+/**
+ * Shared handle on a running indexing pass, used to watch its progress and to cancel it.
+ */
+pub struct IndexingMonitor {
+    started: Instant,
+    expected_files: Option<u64>, // files scanned by the previous pass, if known.
+    cancelled: AtomicBool,
+    state: Mutex<MonitorState>,
+}
+
+struct MonitorState {
+    pass: IndexingPass,
+    current_path: Option<PathBuf>,
+    finished: bool,
+    error: Option<String>,
+}
+
+/**
+ * Snapshot of the progress of an indexing pass.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct IndexingProgress {
+    #[serde(flatten)]
+    pub pass: IndexingPass, // counts so far, duration_millis is the time elapsed while running.
+    pub current_path: Option<PathBuf>, // file being indexed.
+    pub expected_files: Option<u64>,
+    pub eta_seconds: Option<u64>, // estimated time remaining based on the previous pass, None if unknown.
+    pub running: bool,
+    pub cancelled: bool,
+    pub error: Option<String>, // why the pass failed.
+}
+
+impl IndexingMonitor {
+    pub fn new(expected_files: Option<u64>) -> IndexingMonitor {
+        return IndexingMonitor {
+            started: Instant::now(),
+            expected_files: expected_files,
+            cancelled: AtomicBool::new(false),
+            state: Mutex::new(MonitorState {
+                pass: IndexingPass {
+                    started: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs())
+                        .unwrap_or(0),
+                    ..IndexingPass::default()
+                },
+                current_path: None,
+                finished: false,
+                error: None,
+            }),
+        };
+    }
+
+    /**
+     * Asks the pass to stop, it stops before the next file.
+     */
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.state.lock().unwrap().finished;
+    }
+
+    fn update(&self, pass: &IndexingPass, current_path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.pass = pass.clone();
+        state.current_path = Some(PathBuf::from(current_path));
+    }
+
+    fn finish(&self, pass: Option<&IndexingPass>, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pass) = pass {
+            state.pass = pass.clone();
+        }
+        state.pass.duration_millis = self.started.elapsed().as_millis() as u64;
+        state.current_path = None;
+        state.finished = true;
+        state.error = error;
+    }
+
+    pub fn progress(&self) -> IndexingProgress {
+        let state = self.state.lock().unwrap();
+        let mut pass = state.pass.clone();
+        if !state.finished {
+            pass.duration_millis = self.started.elapsed().as_millis() as u64;
+        }
+        let eta_seconds = match self.expected_files {
+            Some(expected) if !state.finished && pass.scanned > 0 && expected >= pass.scanned => {
+                Some(pass.duration_millis * (expected - pass.scanned) / pass.scanned / 1000)
+            }
+            _ => None,
+        };
+        return IndexingProgress {
+            pass: pass,
+            current_path: state.current_path.clone(),
+            expected_files: self.expected_files,
+            eta_seconds: eta_seconds,
+            running: !state.finished,
+            cancelled: self.is_cancelled(),
+            error: state.error.clone(),
+        };
+    }
+}
 
 pub struct IndexerWorker {
     paths: Vec<PathBuf>,
     metadata_providers: Vec<Box<dyn MetadataProvider>>,
+    monitor: Option<Arc<IndexingMonitor>>,
 }
 
 impl IndexerWorker {
@@ -20,16 +227,41 @@ impl IndexerWorker {
         return IndexerWorker {
             paths: paths.clone(),
             metadata_providers: metadata_providers,
+            monitor: None,
         };
     }
 
     /**
-     * Runs an indexing pass writing to the IndexWriter
+     * Runs an indexing pass writing to the IndexWriter. Files and directories that can't be read are reported and
+     * counted as errors rather than ending the pass.
      */
-    pub fn index(&mut self, to: &mut dyn IndexWriter) -> Result<()> {
-        for path in &self.paths.clone() {
+    pub fn index(&mut self, to: &mut dyn IndexWriter) -> Result<IndexingPass> {
+        let started = Instant::now();
+        let mut pass = IndexingPass {
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            ..IndexingPass::default()
+        };
+
+        'paths: for path in &self.paths.clone() {
             for entry in jwalk::WalkDir::new(path) {
-                let entry = entry?;
+                if let Some(monitor) = &self.monitor {
+                    if monitor.is_cancelled() {
+                        println!("indexing pass cancelled");
+                        break 'paths;
+                    }
+                }
+
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        println!("failed to read directory entry: {}", e);
+                        pass.errors += 1;
+                        continue;
+                    }
+                };
 
                 println!("indexing {:?}", entry.path());
 
@@ -38,12 +270,27 @@ impl IndexerWorker {
                 }
 
                 if entry.file_type().is_file() {
-                    self.index_file(&entry.path().as_path(), to)
-                        .context(format!("failed to index {:?}", &entry.path()))?;
+                    if let Some(monitor) = &self.monitor {
+                        monitor.update(&pass, &entry.path());
+                    }
+                    pass.scanned += 1;
+                    match self
+                        .index_file(&entry.path().as_path(), to)
+                        .context(format!("failed to index {:?}", &entry.path()))
+                    {
+                        Ok(Some(_)) => pass.indexed += 1,
+                        Ok(None) => pass.skipped += 1,
+                        Err(e) => {
+                            println!("{:#}", e);
+                            pass.errors += 1;
+                        }
+                    }
                 }
             }
         }
-        return Ok(());
+
+        pass.duration_millis = started.elapsed().as_millis() as u64;
+        return Ok(pass);
     }
 
     fn index_directory(&mut self, dir: &Path, to: &mut dyn IndexWriter) -> Result<()> {
@@ -81,7 +328,7 @@ impl IndexerWorker {
 
         println!("file {}", file.to_string_lossy());
 
-        if !to.should_add_document(file) {
+        if !to.should_add_document(file)? {
             println!("skipping indexing file, already indexed.");
             return Ok(None);
         }
@@ -169,3 +416,65 @@ pub mod metadata_providers {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tantivy_backend::TantivyIndex;
+    use crate::index::testing::TempDir;
+    use crate::index::{SearchOptions, SearchableIndex};
+
+    fn write_files(dir: &TempDir) -> Vec<PathBuf> {
+        fs::create_dir_all(dir.path.join("docs")).unwrap();
+        fs::write(
+            dir.path.join("docs/minutes.txt"),
+            "quarterly planning minutes",
+        )
+        .unwrap();
+        fs::write(dir.path.join("docs/notes.md"), "holiday rota").unwrap();
+        fs::write(dir.path.join("readme.txt"), "welcome").unwrap();
+        return vec![dir.path.clone()];
+    }
+
+    #[test]
+    fn indexing_pass_makes_files_searchable() {
+        let dir = TempDir::new("indexing-pass");
+        let paths = write_files(&dir);
+        let index = TantivyIndex::create_in_memory().unwrap();
+
+        let pass = run_indexing_pass(&paths, &index, None).unwrap();
+        assert_eq!((pass.scanned, pass.indexed, pass.skipped), (3, 3, 0));
+        let recorded = index.last_indexing_pass().unwrap().unwrap();
+        assert_eq!((recorded.started, recorded.indexed), (pass.started, 3));
+
+        let results = index
+            .search("quarterly", 10, 0, &SearchOptions::default())
+            .unwrap();
+        assert_eq!(results.total_hits, 1);
+        assert_eq!(
+            results.hits[0].document.metadata.path,
+            dir.path.join("docs/minutes.txt")
+        );
+
+        // unchanged files are skipped by the next pass.
+        let pass = run_indexing_pass(&paths, &index, None).unwrap();
+        assert_eq!((pass.scanned, pass.indexed, pass.skipped), (3, 0, 3));
+    }
+
+    #[test]
+    fn cancelled_pass_is_not_recorded() {
+        let dir = TempDir::new("cancelled-pass");
+        let paths = write_files(&dir);
+        let index = TantivyIndex::create_in_memory().unwrap();
+
+        let monitor = Arc::new(IndexingMonitor::new(None));
+        monitor.cancel();
+        let pass = run_indexing_pass(&paths, &index, Some(monitor.clone())).unwrap();
+        assert_eq!((pass.scanned, pass.indexed), (0, 0));
+        assert!(index.last_indexing_pass().unwrap().is_none());
+
+        let pass = run_indexing_pass(&paths, &index, None).unwrap();
+        assert_eq!(pass.indexed, 3);
+        assert!(index.last_indexing_pass().unwrap().is_some());
+    }
+}
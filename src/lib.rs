@@ -0,0 +1,8 @@
+#![allow(dead_code)]
+
+pub mod api;
+pub mod config;
+pub mod index;
+pub mod indexer_worker;
+pub mod scheduler;
+pub mod webserver;
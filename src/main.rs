@@ -1,23 +1,24 @@
 #![allow(dead_code)]
 
-pub mod config;
-pub mod index;
-pub mod indexer_worker;
-pub mod webserver;
-
-use crate::config::Config;
-use crate::index::tantivy_backend::*;
-use crate::index::*;
-use crate::indexer_worker::{
-    metadata_providers::BasicAttributesMetadataProvider, IndexerWorker, MetadataProvider,
-};
+use guidebook::config::{Config, TlsConfig};
+use guidebook::index::generations;
+use guidebook::index::metadata_store::{copy_metadata, MetadataBackend};
+use guidebook::index::tantivy_backend::*;
+use guidebook::index::*;
+use guidebook::indexer_worker::run_indexing_pass;
+use guidebook::scheduler::Scheduler;
+use guidebook::{api, webserver};
 use anyhow::Context;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHasher};
 use clap::{App, Arg, SubCommand};
 use std::borrow::BorrowMut;
 use std::io::{BufRead, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use std::{fs, io};
 
 fn main() {
@@ -41,9 +42,97 @@ fn main() {
                 .help("Updates the index")
                 .takes_value(false),
         )
-        .subcommand(SubCommand::with_name("startweb").about("starts the web ui"))
-        .subcommand(SubCommand::with_name("cli").about("starts the CLI search interface"));
+        .subcommand(
+            SubCommand::with_name("startweb")
+                .about("starts the web ui, running indexing passes on the configured schedule")
+                .arg(
+                    Arg::new("address")
+                        .long("address")
+                        .help("address to listen on, overrides web.address in the config")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .help("port to listen on, overrides web.port in the config")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("tls_certs")
+                        .long("tls-certs")
+                        .help("PEM certificate chain to serve https with, overrides web.tls in the config")
+                        .takes_value(true)
+                        .requires("tls_key"),
+                )
+                .arg(
+                    Arg::new("tls_key")
+                        .long("tls-key")
+                        .help("PEM private key for the certificate chain given by --tls-certs")
+                        .takes_value(true)
+                        .requires("tls_certs"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("cli").about("starts the CLI search interface"))
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("runs indexing passes on the configured schedule without the web ui"),
+        )
+        .subcommand(
+            SubCommand::with_name("hash-password")
+                .about("reads a password from stdin and prints its hash for the auth.users section of the config"),
+        )
+        .subcommand(
+            SubCommand::with_name("openapi")
+                .about("prints the OpenAPI spec of the web server's versioned api"),
+        )
+        .subcommand(
+            SubCommand::with_name("rebuild")
+                .about("builds a fresh index from scratch and swaps it in once it is complete")
+                .arg(
+                    Arg::new("from_store")
+                        .long("from-store")
+                        .help("rebuilds from the metadata and content in the keyvalue store rather than the filesystem")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert-kvstore")
+                .about("converts the keyvalue store to another format and swaps it in")
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("format to convert to, one of lmdb, sqlite or leveldb")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("merges the index into a single segment and compacts the keyvalue store, reporting the space reclaimed"),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("prints document counts, index size and the outcome of the last indexing pass"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("checks the index and keyvalue store agree with each other and the filesystem")
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .help("drops inconsistent entries so the next indexing pass re-indexes them")
+                        .takes_value(false),
+                ),
+        );
     let m = app.clone().get_matches();
+    if let Some(_) = m.subcommand_matches("hash-password") {
+        hash_password();
+        return;
+    }
+    if let Some(_) = m.subcommand_matches("openapi") {
+        println!("{:#}", api::openapi_spec());
+        return;
+    }
 
     // Load configuration
     let config_path = PathBuf::from(m.value_of("config").unwrap());
@@ -58,9 +147,29 @@ fn main() {
     // Open the database (creating it if it does not exist)
     fs::create_dir_all(&config.database_location)
         .expect("failed to create directory for the index");
-    let database_path = PathBuf::from(&config.database_location);
-    let mut index =
-        TantivyIndex::create(&database_path.as_path()).expect("failed to create the index");
+    let database_location = PathBuf::from(&config.database_location);
+    if let Some(rebuild_matches) = m.subcommand_matches("rebuild") {
+        rebuild_index(
+            &config,
+            &database_location,
+            rebuild_matches.is_present("from_store"),
+        );
+        return;
+    }
+    if let Some(convert_matches) = m.subcommand_matches("convert-kvstore") {
+        let to = MetadataBackend::from_str(convert_matches.value_of("to").unwrap())
+            .expect("invalid format");
+        convert_kvstore(&database_location, to);
+        return;
+    }
+    if let Some(_) = m.subcommand_matches("compact") {
+        compact_index(&config, &database_location);
+        return;
+    }
+
+    let database_path =
+        generations::current_dir(&database_location).expect("failed to locate the current index");
+    let index = open_index(&config, &database_path).expect("failed to create the index");
 
     // Run an indexing pass
     if m.is_present("update_index") {
@@ -69,14 +178,78 @@ fn main() {
 
     if let Some(_) = m.subcommand_matches("cli") {
         search_cli(index.as_searchable());
-    } else if let Some(_) = m.subcommand_matches("startweb") {
-        webserver::set_state(Arc::new(index));
-        webserver::serve();
+    } else if let Some(check_matches) = m.subcommand_matches("check") {
+        check_index(&index, check_matches.is_present("repair"));
+    } else if let Some(_) = m.subcommand_matches("daemon") {
+        match Scheduler::create(&config).expect("invalid schedule") {
+            Some(scheduler) => {
+                let current: Mutex<(PathBuf, Arc<dyn Index>)> =
+                    Mutex::new((database_path, Arc::new(index)));
+                scheduler.run(Box::new(move || {
+                    return current_index(&config, &database_location, &current);
+                }))
+            }
+            None => println!("no indexed directory has a schedule, add one to the config"),
+        }
+    } else if let Some(_) = m.subcommand_matches("stats") {
+        print_stats(index.as_searchable(), &config);
+    } else if let Some(web_matches) = m.subcommand_matches("startweb") {
+        let mut config = config;
+        if let Some(address) = web_matches.value_of("address") {
+            config.web.address = address.parse().expect("invalid --address");
+        }
+        if let Some(port) = web_matches.value_of("port") {
+            config.web.port = port.parse().expect("invalid --port");
+        }
+        if let (Some(certs), Some(key)) = (
+            web_matches.value_of("tls_certs"),
+            web_matches.value_of("tls_key"),
+        ) {
+            config.web.tls = Some(TlsConfig {
+                certs: certs.to_string(),
+                key: key.to_string(),
+            });
+        }
+
+        let config = Arc::new(config);
+        webserver::set_state(Arc::new(index), config.clone())
+            .expect("failed to set up the web server");
+        watch_for_rebuilds(database_location, database_path, config.clone());
+        if let Some(scheduler) = Scheduler::create(&config).expect("invalid schedule") {
+            scheduler.start(Box::new(|| webserver::get_state().db));
+        }
+        webserver::serve(&config.web).expect("failed to run the web server");
     } else {
         app.print_help().unwrap();
     }
 }
 
+/**
+ * Opens the index in a generation directory with the content and merge policy settings from the config.
+ */
+fn open_index(config: &Config, dir: &Path) -> anyhow::Result<TantivyIndex> {
+    let mut index = TantivyIndex::create(dir, config.metadata_store)?;
+    index.set_store_content(config.store_content);
+    index.set_merge_policy(config.merge_policy.clone());
+    return Ok(index);
+}
+
+fn hash_password() {
+    print!("password: ");
+    let _ = std::io::stdout().flush();
+    let mut password = String::new();
+    io::stdin()
+        .read_line(&mut password)
+        .expect("failed to read the password");
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("failed to hash the password");
+    println!("{}", hash);
+}
+
 fn search_cli(index: &dyn SearchableIndex) {
     let stdin = io::stdin();
     print!("query: ");
@@ -88,13 +261,17 @@ fn search_cli(index: &dyn SearchableIndex) {
         let now = SystemTime::now();
 
         println!("searching...");
-        let documents = index
-            .search(&line, 10, 0)
+        let results = index
+            .search(&line, 10, 0, &SearchOptions::default())
             .expect("failed to execute the query.");
 
-        for document in documents {
-            println!("{:?}", document);
+        for hit in results.hits {
+            println!("{:.3} {:?}", hit.score, hit.document);
         }
+        println!(
+            "{} total hits, query took {} micros",
+            results.total_hits, results.latency_micros
+        );
 
         println!("took: {} millis", now.elapsed().unwrap().as_millis());
         print!("query: ");
@@ -102,23 +279,343 @@ fn search_cli(index: &dyn SearchableIndex) {
     }
 }
 
-fn do_indexing(config: &Config, index: &mut dyn WritableIndex) {
-    let writer = &mut index
-        .begin_add_documents()
-        .expect("Failed to get a document writer");
+fn check_index(index: &TantivyIndex, repair: bool) {
+    let report = index.check(repair).expect("failed to check the index");
 
-    let mut paths: Vec<PathBuf> = Vec::new();
-    for indexed_dir in &config.indexed_directories {
-        paths.push(PathBuf::from(&indexed_dir.path));
+    let sections = [
+        ("indexed without metadata", &report.missing_metadata),
+        ("metadata without an indexed document", &report.missing_from_index),
+        ("metadata that fails to deserialize", &report.invalid_metadata),
+        ("no longer exist on disk", &report.missing_on_disk),
+    ];
+    for (description, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        println!("{} paths {}:", paths.len(), description);
+        for path in paths {
+            println!("  {}", path);
+        }
     }
 
-    let mut providers: Vec<Box<dyn MetadataProvider>> = Vec::new();
-    providers.push(Box::new(BasicAttributesMetadataProvider::new()));
+    println!(
+        "checked {} indexed documents and {} metadata entries",
+        report.tantivy_documents, report.metadata_documents
+    );
+    if report.is_consistent() {
+        println!("index is consistent");
+    } else if report.repaired {
+        println!("repaired, run with --update_index to re-index paths that still exist");
+    } else {
+        println!("index is inconsistent, run with --repair to reconcile it");
+    }
+}
 
-    let mut worker = IndexerWorker::create(&paths, providers);
-    worker.index(writer.as_mut()).expect("failed to index");
+fn print_stats(index: &dyn SearchableIndex, config: &Config) {
+    let stats = index
+        .stats(&config.indexed_paths())
+        .expect("failed to collect index stats");
+
+    println!("documents: {}", stats.total_documents);
+    for root in &stats.roots {
+        println!(
+            "  {}: {} documents, {} bytes",
+            root.path.to_string_lossy(),
+            root.documents,
+            root.bytes
+        );
+    }
+    println!("bytes indexed: {}", stats.bytes_indexed);
+    if stats.invalid_documents > 0 {
+        println!(
+            "{} documents have unreadable metadata, run check --repair to drop them",
+            stats.invalid_documents
+        );
+    }
+    println!(
+        "index: {} segments, {} bytes on disk",
+        stats.segments, stats.index_size_bytes
+    );
+    println!(
+        "keyvalue store: {} bytes on disk",
+        stats.metadata_size_bytes
+    );
+    match &stats.last_indexing_pass {
+        Some(pass) => {
+            let started = SystemTime::UNIX_EPOCH + Duration::from_secs(pass.started);
+            let ago = SystemTime::now()
+                .duration_since(started)
+                .unwrap_or_default()
+                .as_secs();
+            println!(
+                "last indexing pass: {}s ago, took {:.1}s, {} scanned, {} indexed, {} skipped, {} errors",
+                ago,
+                pass.duration_millis as f64 / 1000.0,
+                pass.scanned,
+                pass.indexed,
+                pass.skipped,
+                pass.errors
+            );
+        }
+        None => println!("last indexing pass: never"),
+    }
+
+    for (title, counts) in [
+        ("extensions", &stats.extensions),
+        ("mime types", &stats.mime_types),
+    ] {
+        println!("{}:", title);
+        for count in counts {
+            println!("  {}: {}", count.value, count.count);
+        }
+    }
+}
+
+fn rebuild_index(config: &Config, database_location: &Path, from_store: bool) {
+    let previous =
+        generations::current_dir(database_location).expect("failed to locate the current index");
+    let current = if from_store {
+        let current = TantivyIndex::create(&previous.as_path(), config.metadata_store)
+            .expect("failed to open the index");
+        current
+            .ensure_content_stored()
+            .expect("refusing to rebuild from the keyvalue store");
+        Some(current)
+    } else {
+        None
+    };
+    let generation_dir = generations::create_generation(database_location)
+        .expect("failed to create a new generation");
+    println!(
+        "rebuilding the index in {}",
+        generation_dir.to_string_lossy()
+    );
+
+    if let Some(current) = current {
+        // opening an index whose tantivy directory is missing rebuilds it from the keyvalue store.
+        current
+            .copy_keyvalue_store(&generation_dir.join("kvstore"))
+            .expect("failed to copy the keyvalue store");
+        drop(current);
+        TantivyIndex::create(&generation_dir.as_path(), config.metadata_store)
+            .expect("failed to rebuild the index");
+    } else {
+        let index = open_index(config, &generation_dir).expect("failed to create the index");
+        do_indexing(config, index.as_writable());
+    }
+
+    generations::set_current(database_location, &generation_dir)
+        .expect("failed to swap in the rebuilt index");
+    println!("swapped in the rebuilt index");
+
+    // the previous generation is kept so a running web server can finish in-flight searches against it
+    generations::remove_stale(
+        database_location,
+        &[generation_dir.as_path(), previous.as_path()],
+    )
+    .expect("failed to remove stale index generations");
+}
+
+/**
+ * Copies the current index into a new generation with its keyvalue store converted to the given format, then swaps the
+ * new generation in.
+ */
+fn convert_kvstore(database_location: &Path, to: MetadataBackend) {
+    let previous =
+        generations::current_dir(database_location).expect("failed to locate the current index");
+    let from = MetadataBackend::detect(&previous.join("kvstore")).expect("no keyvalue store found");
+    if from == to {
+        println!("keyvalue store is already {:?}", to);
+        return;
+    }
+
+    let lock = match lock_index_writer(&previous).expect("failed to lock the index") {
+        Some(lock) => lock,
+        None => {
+            eprintln!("the index is being written by another process, try again once it finishes");
+            std::process::exit(1);
+        }
+    };
+    let generation_dir = generations::create_generation(database_location)
+        .expect("failed to create a new generation");
+    println!(
+        "converting the keyvalue store from {:?} to {:?} in {}",
+        from,
+        to,
+        generation_dir.to_string_lossy()
+    );
+
+    {
+        let source = from
+            .open(&previous.join("kvstore"))
+            .expect("failed to open the keyvalue store");
+        let target = to
+            .open(&generation_dir.join("kvstore"))
+            .expect("failed to create the converted keyvalue store");
+        copy_metadata(source.as_ref(), target.as_ref())
+            .expect("failed to convert the keyvalue store");
+    }
+
+    // the tantivy index is format independent, copy it across as is.
+    copy_index_files(&previous, &generation_dir);
+    drop(lock);
+
+    // open the converted generation once to make sure it is usable before swapping it in.
+    TantivyIndex::create(&generation_dir.as_path(), to)
+        .expect("failed to open the converted index");
+    generations::set_current(database_location, &generation_dir)
+        .expect("failed to swap in the converted index");
+    generations::remove_stale(
+        database_location,
+        &[generation_dir.as_path(), previous.as_path()],
+    )
+    .expect("failed to remove stale index generations");
+    println!(
+        "converted, set metadata_store: {} in the config to use it",
+        format!("{:?}", to).to_lowercase()
+    );
+}
+
+/**
+ * Copies the current index into a new generation, compacting the keyvalue store on the way and merging the tantivy
+ * index down to a single segment, then swaps the new generation in.
+ */
+fn compact_index(config: &Config, database_location: &Path) {
+    let previous =
+        generations::current_dir(database_location).expect("failed to locate the current index");
+    let current = TantivyIndex::create(&previous.as_path(), config.metadata_store)
+        .expect("failed to open the index");
+    let lock = match lock_index_writer(&previous).expect("failed to lock the index") {
+        Some(lock) => lock,
+        None => {
+            eprintln!("the index is being written by another process, try again once it finishes");
+            std::process::exit(1);
+        }
+    };
+    let generation_dir = generations::create_generation(database_location)
+        .expect("failed to create a new generation");
+    println!(
+        "compacting the index into {}",
+        generation_dir.to_string_lossy()
+    );
+
+    // copying the keyvalue store compacts it, e.g. lmdb's copy-compact skips free pages.
+    current
+        .copy_keyvalue_store(&generation_dir.join("kvstore"))
+        .expect("failed to copy the keyvalue store");
+    drop(current);
+    copy_index_files(&previous, &generation_dir);
+    drop(lock);
+
+    let index = open_index(config, &generation_dir).expect("failed to open the compacted index");
+    let segments = index.force_merge().expect("failed to merge the index");
+    drop(index);
+
+    let size = |dir: &Path| {
+        return dir_size(&dir.join("index")).expect("failed to measure the index")
+            + dir_size(&dir.join("kvstore")).expect("failed to measure the keyvalue store");
+    };
+    let size_before = size(&previous);
+    let size_after = size(&generation_dir);
+
+    generations::set_current(database_location, &generation_dir)
+        .expect("failed to swap in the compacted index");
+    generations::remove_stale(
+        database_location,
+        &[generation_dir.as_path(), previous.as_path()],
+    )
+    .expect("failed to remove stale index generations");
+    println!(
+        "merged {} segments, compacted the index from {} to {} bytes, reclaiming {} bytes",
+        segments,
+        size_before,
+        size_after,
+        size_before.saturating_sub(size_after)
+    );
+}
+
+/**
+ * Copies the files of the tantivy index from one generation into another. The caller holds the writer lock on the
+ * source so that no commit lands midway through the copy.
+ */
+fn copy_index_files(from: &Path, to: &Path) {
+    let index_dir = to.join("index");
+    fs::create_dir(&index_dir).expect("failed to create the index directory");
+    for entry in fs::read_dir(from.join("index")).expect("failed to read the index directory") {
+        let path = entry.expect("failed to read the index directory").path();
+        if path.is_file() {
+            fs::copy(&path, index_dir.join(path.file_name().unwrap()))
+                .expect("failed to copy the index");
+        }
+    }
+}
+
+/**
+ * Polls the current generation pointer and hands the web server a freshly opened index whenever a rebuild swaps one in.
+ */
+fn watch_for_rebuilds(database_location: PathBuf, current: PathBuf, config: Arc<Config>) {
+    std::thread::spawn(move || {
+        let mut current = current;
+        loop {
+            std::thread::sleep(Duration::from_secs(5));
+            let latest = match generations::current_dir(&database_location) {
+                Ok(latest) => latest,
+                Err(e) => {
+                    println!("failed to read the current index generation: {:?}", e);
+                    continue;
+                }
+            };
+            if latest == current {
+                continue;
+            }
+
+            match open_index(&config, &latest) {
+                Ok(index) => {
+                    println!("switching to rebuilt index {}", latest.to_string_lossy());
+                    if let Err(e) = webserver::set_state(Arc::new(index), config.clone()) {
+                        println!("failed to switch to the rebuilt index: {:?}", e);
+                        continue;
+                    }
+                    current = latest;
+                }
+                Err(e) => println!(
+                    "failed to open rebuilt index {}: {:?}",
+                    latest.to_string_lossy(),
+                    e
+                ),
+            }
+        }
+    });
+}
+
+/**
+ * The index of the current generation for the daemon's next pass, reopened whenever a rebuild has swapped in another
+ * generation since the last pass.
+ */
+fn current_index(
+    config: &Config,
+    database_location: &Path,
+    current: &Mutex<(PathBuf, Arc<dyn Index>)>,
+) -> Arc<dyn Index> {
+    let mut current = current.lock().unwrap();
+    match generations::current_dir(database_location) {
+        Ok(latest) if latest != current.0 => match open_index(config, &latest) {
+            Ok(index) => {
+                println!("switching to rebuilt index {}", latest.to_string_lossy());
+                *current = (latest, Arc::new(index));
+            }
+            Err(e) => println!(
+                "failed to open rebuilt index {}: {:?}",
+                latest.to_string_lossy(),
+                e
+            ),
+        },
+        Ok(_) => {}
+        Err(e) => println!("failed to read the current index generation: {:?}", e),
+    }
+    return current.1.clone();
+}
 
-    writer
-        .commit()
-        .expect("Failed to commit newly indexed documents. Uh oh.");
+fn do_indexing(config: &Config, index: &dyn WritableIndex) {
+    run_indexing_pass(&config.indexed_paths(), index, None).expect("failed to index");
 }
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, ScheduleConfig};
+use crate::index::Index;
+use crate::indexer_worker::{start_indexing_pass, IndexingMonitor};
+use anyhow::{Context, Error, Result};
+use chrono::{Local, TimeZone};
+use rand::Rng;
+
+// file under the database location recording when each directory last had a scheduled indexing pass.
+const LAST_RUNS_FILE: &str = "schedule.json";
+// how often the scheduler checks for directories that are due.
+const TICK: Duration = Duration::from_secs(30);
+// delay before retrying a scheduled pass that failed, e.g. because another process was writing to the index.
+const RETRY_DELAY_SECS: u64 = 300;
+
+/**
+ * When a directory is indexed, parsed from a ScheduleConfig.
+ */
+pub enum Schedule {
+    Interval(Duration),
+    Cron(cron::Schedule),
+}
+
+impl Schedule {
+    pub fn from_config(config: &ScheduleConfig) -> Result<Schedule> {
+        return match (&config.interval, &config.cron) {
+            (Some(interval), None) => Ok(Schedule::Interval(
+                humantime::parse_duration(interval)
+                    .context(format!("invalid schedule interval {:?}", interval))?,
+            )),
+            (None, Some(expression)) => {
+                // the cron crate expects a leading seconds field, five field expressions run on the minute.
+                let expression = if expression.split_whitespace().count() == 5 {
+                    format!("0 {}", expression)
+                } else {
+                    expression.clone()
+                };
+                let schedule = cron::Schedule::from_str(&expression).map_err(|e| {
+                    Error::msg(format!("invalid cron expression {:?}: {}", expression, e))
+                })?;
+                Ok(Schedule::Cron(schedule))
+            }
+            _ => Err(Error::msg(
+                "a schedule needs exactly one of interval or cron",
+            )),
+        };
+    }
+
+    /**
+     * Time of the first run after the last run in seconds since the unix epoch, None if there never is one. A directory
+     * that has never run on schedule runs straight away on an interval, and at the next matching time for cron.
+     */
+    fn next_run(&self, last_run: Option<u64>, now: u64) -> Option<u64> {
+        return match self {
+            Schedule::Interval(interval) => {
+                Some(last_run.map_or(now, |last_run| last_run + interval.as_secs()))
+            }
+            Schedule::Cron(schedule) => {
+                let after = Local.timestamp(last_run.unwrap_or(now) as i64, 0);
+                schedule
+                    .after(&after)
+                    .next()
+                    .map(|time| time.timestamp() as u64)
+            }
+        };
+    }
+}
+
+struct ScheduledDirectory {
+    path: PathBuf,
+    schedule: Schedule,
+    jitter: Duration,
+    next_run: Option<u64>, // seconds since the unix epoch including jitter.
+}
+
+/**
+ * Runs indexing passes over the indexed directories on their configured schedules. Directories that are due at the
+ * same time share a pass, and a pass never starts while another is running in this process, it waits for the running
+ * pass to finish instead.
+ */
+pub struct Scheduler {
+    directories: Vec<ScheduledDirectory>,
+    // start time of the last scheduled pass over each directory, persisted so restarts keep to the schedule.
+    last_runs: HashMap<String, u64>,
+    last_runs_path: PathBuf,
+    running: Option<(Vec<usize>, Arc<IndexingMonitor>)>,
+}
+
+impl Scheduler {
+    /**
+     * Loads the configured schedules and the persisted last run times, None if no directory has a schedule.
+     */
+    pub fn create(config: &Config) -> Result<Option<Scheduler>> {
+        let mut directories: Vec<ScheduledDirectory> = Vec::new();
+        for indexed_dir in &config.indexed_directories {
+            let schedule = match indexed_dir.schedule.as_ref().or(config.schedule.as_ref()) {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+            let jitter = match &schedule.jitter {
+                Some(jitter) => humantime::parse_duration(jitter)
+                    .context(format!("invalid schedule jitter {:?}", jitter))?,
+                None => Duration::ZERO,
+            };
+            directories.push(ScheduledDirectory {
+                path: PathBuf::from(&indexed_dir.path),
+                schedule: Schedule::from_config(schedule)
+                    .context(format!("invalid schedule for {}", indexed_dir.path))?,
+                jitter: jitter,
+                next_run: None,
+            });
+        }
+        if directories.is_empty() {
+            return Ok(None);
+        }
+
+        let last_runs_path = PathBuf::from(&config.database_location).join(LAST_RUNS_FILE);
+        let last_runs: HashMap<String, u64> = if last_runs_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&last_runs_path)?).context(format!(
+                "failed to parse {}",
+                last_runs_path.to_string_lossy()
+            ))?
+        } else {
+            HashMap::new()
+        };
+
+        let mut scheduler = Scheduler {
+            directories: directories,
+            last_runs: last_runs,
+            last_runs_path: last_runs_path,
+            running: None,
+        };
+        for i in 0..scheduler.directories.len() {
+            scheduler.plan(i, now());
+        }
+        return Ok(Some(scheduler));
+    }
+
+    /**
+     * Runs the scheduler on a background thread. Each pass is run against the index returned by `index` at the time,
+     * so the web server's index can be swapped out by a rebuild.
+     */
+    pub fn start(self, index: Box<dyn Fn() -> Arc<dyn Index> + Send>) {
+        std::thread::spawn(move || self.run(index));
+    }
+
+    /**
+     * Runs the scheduler on the current thread, it never returns.
+     */
+    pub fn run(mut self, index: Box<dyn Fn() -> Arc<dyn Index> + Send>) {
+        loop {
+            self.tick(index.as_ref());
+            std::thread::sleep(TICK);
+        }
+    }
+
+    fn tick(&mut self, index: &dyn Fn() -> Arc<dyn Index>) {
+        let now = now();
+        if let Some((due, monitor)) = self.running.take() {
+            if !monitor.is_finished() {
+                self.running = Some((due, monitor));
+                return;
+            }
+            self.finished(&due, &monitor, now);
+        }
+
+        let due: Vec<usize> = (0..self.directories.len())
+            .filter(|i| {
+                self.directories[*i]
+                    .next_run
+                    .map_or(false, |next_run| next_run <= now)
+            })
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+
+        let paths: Vec<PathBuf> = due
+            .iter()
+            .map(|i| self.directories[*i].path.clone())
+            .collect();
+        // None when a pass started through the web server is running, the directories stay due until it finishes.
+        if let Some(monitor) = start_indexing_pass(paths.clone(), index()) {
+            println!("started scheduled indexing pass over {:?}", paths);
+            self.running = Some((due, monitor));
+        }
+    }
+
+    /**
+     * Records the outcome of a scheduled pass and plans the next run of its directories.
+     */
+    fn finished(&mut self, due: &Vec<usize>, monitor: &IndexingMonitor, now: u64) {
+        let progress = monitor.progress();
+        for i in due {
+            if progress.error.is_some() {
+                self.directories[*i].next_run = Some(now + RETRY_DELAY_SECS);
+                continue;
+            }
+            // a cancelled pass counts as a run, otherwise it would start again straight away.
+            let key = self.directories[*i].path.to_string_lossy().to_string();
+            self.last_runs.insert(key, progress.pass.started);
+            self.plan(*i, now);
+        }
+
+        if let Err(e) = self.save_last_runs() {
+            println!("failed to save the last scheduled run times: {:#}", e);
+        }
+    }
+
+    fn plan(&mut self, i: usize, now: u64) {
+        let dir = &mut self.directories[i];
+        let last_run = self
+            .last_runs
+            .get(dir.path.to_string_lossy().as_ref())
+            .cloned();
+        let jitter = rand::thread_rng().gen_range(0..=dir.jitter.as_secs());
+        dir.next_run = dir
+            .schedule
+            .next_run(last_run, now)
+            .map(|next_run| next_run + jitter);
+
+        match dir.next_run {
+            Some(next_run) => println!(
+                "next scheduled indexing pass of {} at {}",
+                dir.path.to_string_lossy(),
+                Local.timestamp(next_run as i64, 0).to_rfc3339()
+            ),
+            None => println!(
+                "no further scheduled indexing passes of {}",
+                dir.path.to_string_lossy()
+            ),
+        }
+    }
+
+    /**
+     * Atomically replaces the last run times file by renaming a new file over it.
+     */
+    fn save_last_runs(&self) -> Result<()> {
+        let tmp = self.last_runs_path.with_extension("json.tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(serde_json::to_string_pretty(&self.last_runs)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.last_runs_path)?;
+        return Ok(());
+    }
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+
+    fn schedule(interval: Option<&str>, cron: Option<&str>) -> Result<Schedule> {
+        return Schedule::from_config(&ScheduleConfig {
+            interval: interval.map(String::from),
+            cron: cron.map(String::from),
+            jitter: None,
+        });
+    }
+
+    #[test]
+    fn interval_runs_straight_away_then_after_each_interval() {
+        let schedule = schedule(Some("6h"), None).unwrap();
+        assert_eq!(schedule.next_run(None, 1000), Some(1000));
+        assert_eq!(schedule.next_run(Some(500), 1000), Some(500 + 6 * 3600));
+    }
+
+    #[test]
+    fn five_field_cron_runs_on_the_minute() {
+        let schedule = schedule(None, Some("30 3 * * *")).unwrap();
+        let now = now();
+        let next_run = schedule.next_run(None, now).unwrap();
+        assert!(next_run > now && next_run <= now + 24 * 3600);
+        let time = Local.timestamp(next_run as i64, 0);
+        assert_eq!((time.hour(), time.minute(), time.second()), (3, 30, 0));
+
+        // later runs follow the last run rather than the current time.
+        let last_run = next_run + 60;
+        let following_run = schedule.next_run(Some(last_run), now).unwrap();
+        assert!(following_run > last_run && following_run <= last_run + 25 * 3600);
+        let time = Local.timestamp(following_run as i64, 0);
+        assert_eq!((time.hour(), time.minute()), (3, 30));
+    }
+
+    #[test]
+    fn six_field_cron_keeps_its_seconds() {
+        let schedule = schedule(None, Some("15 30 3 * * *")).unwrap();
+        let next_run = schedule.next_run(None, now()).unwrap();
+        let time = Local.timestamp(next_run as i64, 0);
+        assert_eq!((time.hour(), time.minute(), time.second()), (3, 30, 15));
+    }
+
+    #[test]
+    fn cron_without_future_times_never_runs() {
+        let schedule = schedule(None, Some("0 0 3 1 1 * 2000")).unwrap();
+        assert_eq!(schedule.next_run(None, now()), None);
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(schedule(None, None).is_err());
+        assert!(schedule(Some("6h"), Some("0 3 * * *")).is_err());
+        assert!(schedule(Some("often"), None).is_err());
+        assert!(schedule(None, Some("61 3 * * *")).is_err());
+    }
+}
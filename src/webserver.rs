@@ -1,61 +1,232 @@
+use std::collections::HashSet;
 use std::io::Cursor;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use crate::index::{Document, Index};
+use crate::api::{self, ApiError, ErrorCode};
+use crate::config::{AuthConfig, Config, WebConfig};
+use crate::index::{
+    document_path, AccessScope, BrowseEntry, BrowseResults, Index, IndexResult, IndexStats,
+    SortDirection, SortField, SortOrder, UnixUser,
+};
 use anyhow::Result;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use include_dir::{include_dir, Dir};
 use lazy_static::lazy_static;
 use rocket::config::Config as RocketConfig;
+use rocket::config::TlsConfig as RocketTlsConfig;
 use rocket::fs::NamedFile;
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest, Outcome, Request};
 use rocket::response::content::RawText;
 use rocket::response::Responder;
-use rocket::serde::json::{json, Value};
-use rocket::{get, route, routes, Response};
-use serde::{Deserialize, Serialize};
+use rocket::serde::json::{json, Json, Value};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use rocket::{catch, catchers, get, route, routes, Build, Response, Rocket};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use tokio::runtime::Runtime;
 
 pub static WEB_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/web/dist");
 
+// entries returned by a single directory listing.
+const MAX_BROWSE_LIMIT: usize = 1000;
+
+// verified credentials remembered at once, more than there are likely to be users.
+const MAX_VERIFIED_CREDENTIALS: usize = 1024;
+
 #[derive(Clone)]
-struct WebServerState {
-    db: Arc<dyn Index>,
+pub struct WebServerState {
+    pub db: Arc<dyn Index>,
+    pub config: Arc<Config>,
 }
 lazy_static! {
     static ref state: Mutex<Option<WebServerState>> = Mutex::new(None);
+    // digests of basic auth headers that have already been verified, saves hashing the password on every request
+    // without keeping the passwords in memory.
+    static ref verified_credentials: Mutex<HashSet<[u8; 32]>> = Mutex::new(HashSet::new());
+    // key for the digests of verified credentials, chosen at startup so they can't be precomputed.
+    static ref credentials_key: [u8; 32] = rand::random();
 }
 
-pub fn set_state(database: Arc<dyn Index>) -> Result<()> {
+pub fn set_state(database: Arc<dyn Index>, config: Arc<Config>) -> Result<()> {
     state.lock().unwrap().replace(WebServerState {
         db: database.clone(),
+        config: config,
     });
     return Ok(());
 }
 
-fn get_state() -> WebServerState {
+pub fn get_state() -> WebServerState {
     let guard = state.lock().expect("failed to lock state");
     return guard.as_ref().unwrap().clone();
 }
 
-pub fn serve() -> Result<()> {
+pub fn serve(web: &WebConfig) -> Result<()> {
     let mut config = RocketConfig::default();
-    config.address = IpAddr::from([0, 0, 0, 0]);
-    config.port = 8080;
+    config.address = web.address;
+    config.port = web.port;
+    if let Some(tls) = &web.tls {
+        config.tls = Some(RocketTlsConfig::from_paths(&tls.certs, &tls.key));
+    }
+
+    if !get_state().config.auth.is_enabled() {
+        println!(
+            "no users or tokens are configured, the web ui is open to anyone who can reach it"
+        );
+    }
 
-    let rocket_future = rocket::custom(config)
-        .mount("/", routes![route_query, route_index])
-        .launch();
+    let rocket_future = build_rocket(config).launch();
 
     let rt = Runtime::new().unwrap();
-    rt.block_on(rocket_future);
+    rt.block_on(rocket_future)?;
 
     return Ok(());
 }
 
+/**
+ * Mounts the web ui and api on a rocket instance with the given config.
+ */
+fn build_rocket(config: RocketConfig) -> Rocket<Build> {
+    return rocket::custom(config)
+        .mount(
+            "/",
+            routes![route_stats, route_browse, route_file, route_index],
+        )
+        .mount("/", api::routes())
+        .register("/", catchers![catch_unauthorized]);
+}
+
+/**
+ * Request guard admitting requests that carry either http basic auth credentials for a configured user or a configured
+ * bearer token. Every request is admitted when no credentials are configured.
+ */
+pub struct AuthenticatedUser {
+    pub name: Option<String>, // user or token name, None when authentication is disabled.
+    pub scope: AccessScope,   // the documents the user may search.
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let s = get_state();
+        if !s.config.auth.is_enabled() {
+            return Outcome::Success(AuthenticatedUser {
+                name: None,
+                scope: AccessScope::default(),
+            });
+        }
+
+        let header = match request.headers().get_one("Authorization") {
+            Some(header) => header,
+            None => return Outcome::Failure((Status::Unauthorized, "missing credentials")),
+        };
+        return match authenticate(&s.config.auth, header) {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Failure((Status::Unauthorized, "invalid credentials")),
+        };
+    }
+}
+
+/**
+ * Checks the value of an Authorization header against the configured credentials, returning the user or token it
+ * belongs to.
+ */
+fn authenticate(auth: &AuthConfig, header: &str) -> Option<AuthenticatedUser> {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        let token = auth
+            .tokens
+            .iter()
+            .find(|candidate| bool::from(candidate.token.as_bytes().ct_eq(token.as_bytes())))?;
+        return Some(AuthenticatedUser {
+            name: Some(token.name.clone()),
+            scope: auth.scope(
+                &token.name,
+                &token.groups,
+                UnixUser {
+                    uid: token.uid,
+                    gids: token.gids.clone(),
+                },
+            ),
+        });
+    }
+
+    let credentials = base64::decode(header.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    let user = auth.users.iter().find(|user| user.username == username)?;
+    let authenticated = AuthenticatedUser {
+        name: Some(user.username.clone()),
+        scope: auth.scope(
+            &user.username,
+            &user.groups,
+            UnixUser {
+                uid: user.uid,
+                gids: user.gids.clone(),
+            },
+        ),
+    };
+    let digest = credentials_digest(header);
+    if verified_credentials.lock().unwrap().contains(&digest) {
+        return Some(authenticated);
+    }
+
+    let hash = match PasswordHash::new(&user.password_hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            println!("invalid password hash for user {:?}: {}", username, e);
+            return None;
+        }
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .ok()?;
+    let mut verified = verified_credentials.lock().unwrap();
+    // forgetting every credential when the cache fills only costs each user one more password check.
+    if verified.len() >= MAX_VERIFIED_CREDENTIALS {
+        verified.clear();
+    }
+    verified.insert(digest);
+    return Some(authenticated);
+}
+
+/**
+ * Keyed SHA-256 digest of an Authorization header, identifies verified credentials without storing the password.
+ */
+fn credentials_digest(header: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(&*credentials_key);
+    hasher.update(header.as_bytes());
+    return hasher.finalize().into();
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+struct Unauthorized {
+    body: Json<ApiError>,
+    challenge: Header<'static>,
+}
+
+#[catch(401)]
+fn catch_unauthorized() -> Unauthorized {
+    return Unauthorized {
+        body: Json(ApiError::new(
+            ErrorCode::Unauthorized,
+            String::from("valid credentials are required"),
+        )),
+        // prompts browsers for a username and password.
+        challenge: Header::new(
+            "WWW-Authenticate",
+            "Basic realm=\"guidebook\", charset=\"UTF-8\"",
+        ),
+    };
+}
+
 #[get("/<fpath..>")]
-fn route_index<'r>(fpath: PathBuf) -> Option<(ContentType, String)> {
+fn route_index<'r>(fpath: PathBuf, _user: AuthenticatedUser) -> Option<(ContentType, String)> {
     let mut fpath = fpath;
     if fpath.ends_with("/") {
         fpath.push("index.html");
@@ -80,59 +251,637 @@ fn route_index<'r>(fpath: PathBuf) -> Option<(ContentType, String)> {
     ));
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct QueryResult {
-    results: Vec<Document>,
-    error: Option<String>,
-    latency: u64,
+#[get("/api/stats")]
+fn route_stats(user: AuthenticatedUser) -> Result<Json<IndexStats>, ApiError> {
+    api::require_unrestricted(&user)?;
+    let s = get_state();
+    return Ok(Json(s.db.stats(&s.config.indexed_paths())?));
 }
 
-impl QueryResult {
-    fn new(results: Vec<Document>, latency: u128) -> QueryResult {
-        QueryResult {
-            results: results,
-            error: None,
-            latency: latency as u64,
-        }
+/**
+ * Lists the indexed entries within a directory, or the indexed directories themselves when no path is given. Listings
+ * come entirely from the index so browsing never touches the filesystem.
+ */
+#[get("/api/browse?<path>&<offset>&<limit>&<sort>&<order>")]
+fn route_browse(
+    path: Option<String>,
+    offset: Option<String>,
+    limit: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    user: AuthenticatedUser,
+) -> Result<Json<BrowseResults>, ApiError> {
+    let offset: usize = api::parse_param("offset", offset)?.unwrap_or(0);
+    let limit: usize = api::parse_param("limit", limit)?.unwrap_or(100);
+    if limit > MAX_BROWSE_LIMIT {
+        return Err(ApiError::new(
+            ErrorCode::InvalidOption,
+            format!("limit must be <= {}, found: {}", MAX_BROWSE_LIMIT, limit),
+        ));
     }
+    let sort_order = SortOrder {
+        field: api::parse_param("sort", sort)?.unwrap_or(SortField::Name),
+        direction: api::parse_param("order", order)?.unwrap_or(SortDirection::Ascending),
+    };
 
-    fn new_err(error: String) -> QueryResult {
-        QueryResult {
-            results: Vec::new(),
-            error: Some(error),
-            latency: 0,
+    let s = get_state();
+    let results = match path {
+        Some(path) => {
+            s.db.browse(&PathBuf::from(path), limit, offset, sort_order, &user.scope)?
         }
+        None => browse_roots(&s, &user.scope)?,
+    };
+    return Ok(Json(results));
+}
+
+/**
+ * Lists the configured indexed directories that hold documents visible within the scope.
+ */
+fn browse_roots(s: &WebServerState, scope: &AccessScope) -> IndexResult<BrowseResults> {
+    let order = SortOrder {
+        field: SortField::Name,
+        direction: SortDirection::Ascending,
+    };
+    let roots = s.config.indexed_paths();
+    let mut parents: Vec<&Path> = roots
+        .iter()
+        .map(|root| root.parent().unwrap_or(root))
+        .collect();
+    parents.sort();
+    parents.dedup();
+
+    // each root is listed within its parent, roots sharing a parent come from a single listing.
+    let mut listed = Vec::new();
+    for parent in parents {
+        listed.extend(s.db.browse(parent, usize::MAX, 0, order, scope)?.entries);
     }
+    let entries: Vec<BrowseEntry> = roots
+        .iter()
+        .filter_map(|root| listed.iter().find(|entry| &entry.path == root).cloned())
+        .collect();
+
+    return Ok(BrowseResults {
+        path: None,
+        total_entries: entries.len(),
+        entries: entries,
+    });
 }
 
-#[get("/query?<query>&<offset>&<limit>")]
-fn route_query(query: Option<String>, offset: Option<String>, limit: Option<String>) -> Value {
-    if query.is_none() {
-        return json!(QueryResult::new_err(
-            "query parameter is required".to_string()
+/**
+ * Raw value of the Range header, if any.
+ */
+struct RangeHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        return Outcome::Success(RangeHeader(
+            request.headers().get_one("Range").map(String::from),
         ));
     }
-    let query = query.unwrap();
+}
+
+#[derive(Responder)]
+enum FileError {
+    #[response(status = 400)]
+    BadRequest(Value),
+    #[response(status = 404)]
+    NotFound(Value),
+    #[response(status = 416)]
+    RangeNotSatisfiable(Value, Header<'static>),
+}
+
+/**
+ * An indexed file, or a byte range of it, streamed from disk.
+ */
+struct FileResponse {
+    file: File, // positioned at the start of the range.
+    path: PathBuf,
+    size: u64,                 // size of the whole file.
+    range: Option<(u64, u64)>, // inclusive byte range being sent, None when sending the whole file.
+    download: bool,            // ask the browser to save the file rather than display it.
+}
 
-    let offset: u64 = offset.unwrap_or("0".to_string()).parse().unwrap_or(0);
-    let limit: u64 = limit.unwrap_or("25".to_string()).parse().unwrap_or(0);
+impl<'r> Responder<'r, 'static> for FileResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let content_type = mime_guess::from_path(&self.path).first_or_octet_stream();
+        let filename = self.path.file_name().unwrap_or_default().to_string_lossy();
+        // indexed files are untrusted, so they never run scripts in the ui's origin.
+        let download = self.download || is_active_content(&content_type);
 
-    if limit > 500 {
-        return json!(QueryResult::new_err("limit must be <= 100".to_string()));
+        let mut response = Response::build();
+        response
+            .raw_header("Content-Type", content_type.essence_str().to_string())
+            .raw_header("X-Content-Type-Options", "nosniff")
+            .raw_header("Content-Security-Policy", "sandbox")
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header(
+                "Content-Disposition",
+                content_disposition(&filename, download),
+            );
+        match self.range {
+            Some((start, end)) => {
+                let length = end - start + 1;
+                response
+                    .status(Status::PartialContent)
+                    .raw_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, self.size),
+                    )
+                    .raw_header("Content-Length", length.to_string())
+                    .streamed_body(self.file.take(length));
+            }
+            None => {
+                response.sized_body(Some(self.size as usize), self.file);
+            }
+        }
+        return response.ok();
     }
+}
+
+/**
+ * Streams an indexed file the user is allowed to see, `download` asks the browser to save it instead of displaying it.
+ * Supports single byte range requests so that video can be seeked and large downloads resumed.
+ */
+#[get("/file/<id>?<download>")]
+async fn route_file(
+    id: &str,
+    download: Option<bool>,
+    range: RangeHeader,
+    user: AuthenticatedUser,
+) -> Result<FileResponse, FileError> {
+    let not_found = || FileError::NotFound(json!({ "error": "file not found" }));
+    let path = match document_path(id) {
+        Some(path) => path,
+        None => return Err(FileError::BadRequest(json!({ "error": "invalid file id" }))),
+    };
 
+    // only files that are indexed and within the user's scope can be read, anything else looks like a missing file.
     let s = get_state();
+    let document = match s.db.get_document(&path) {
+        Ok(Some(document)) => document,
+        Ok(None) => return Err(not_found()),
+        Err(e) => {
+            println!("failed to look up {:?}: {}", path, e);
+            return Err(not_found());
+        }
+    };
+    if !user.scope.allows(&document.metadata) {
+        return Err(not_found());
+    }
+    let resolved = match resolve_indexed_path(&s.config, &path).await {
+        Some(resolved) => resolved,
+        None => {
+            println!(
+                "refusing to serve {:?}, it does not resolve to a file within an indexed directory",
+                path
+            );
+            return Err(not_found());
+        }
+    };
+
+    let mut file = File::open(&resolved).await.map_err(|_| not_found())?;
+    let metadata = file.metadata().await.map_err(|_| not_found())?;
+    if !metadata.is_file() {
+        return Err(not_found());
+    }
+    let size = metadata.len();
+
+    let range = match range.0 {
+        Some(header) if !header.contains(',') => match parse_range(&header, size) {
+            Some(range) => Some(range),
+            None => {
+                return Err(FileError::RangeNotSatisfiable(
+                    json!({ "error": "range not satisfiable" }),
+                    Header::new("Content-Range", format!("bytes */{}", size)),
+                ))
+            }
+        },
+        // multiple ranges aren't supported, the whole file is sent instead as the spec allows.
+        _ => None,
+    };
+    if let Some((start, _)) = range {
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|_| not_found())?;
+    }
 
-    let now = std::time::SystemTime::now();
-    let res = s.db.search(&query, limit as usize, offset as usize);
-    let took = now.elapsed().unwrap().as_millis();
+    return Ok(FileResponse {
+        file: file,
+        path: resolved,
+        size: size,
+        range: range,
+        download: download.unwrap_or(false),
+    });
+}
+
+/**
+ * Resolves an indexed path to the file on disk. Refuses paths outside the indexed directories, paths containing `..`
+ * and paths that pass through a symlink below an indexed directory, which could otherwise lead outside of it or into a
+ * part of it the user can't see.
+ */
+async fn resolve_indexed_path(config: &Config, path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
 
-    match res {
-        Ok(results) => {
-            return json!(QueryResult::new(results, took));
+    let resolved = rocket::tokio::fs::canonicalize(path).await.ok()?;
+    for root in config.indexed_paths() {
+        let relative = match path.strip_prefix(&root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        // the indexed directory itself may be reached through a symlink, nothing below it may be.
+        if let Ok(root) = rocket::tokio::fs::canonicalize(&root).await {
+            if resolved == root.join(relative) {
+                return Some(resolved);
+            }
         }
-        Err(e) => {
-            return json!(QueryResult::new_err(e.to_string()));
+    }
+    return None;
+}
+
+/**
+ * Parses a single range from a Range header into inclusive start and end offsets within a file of the given size.
+ * Accepts the `bytes=start-end`, `bytes=start-` and `bytes=-suffix_length` forms, None if the range can't be satisfied.
+ */
+fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if size == 0 {
+        return None;
+    }
+
+    let (start, end) = if start.is_empty() {
+        let suffix_length: u64 = end.parse().ok()?;
+        if suffix_length == 0 {
+            return None;
         }
+        (size.saturating_sub(suffix_length), size - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end: u64 = match end {
+            "" => size - 1,
+            end => end.parse::<u64>().ok()?.min(size - 1),
+        };
+        (start, end)
+    };
+    if start > end {
+        return None;
+    }
+    return Some((start, end));
+}
+
+/**
+ * Whether browsers can run scripts in a file of this type when displaying it, such files are always downloaded.
+ */
+fn is_active_content(content_type: &mime_guess::Mime) -> bool {
+    return content_type.essence_str() == "text/html"
+        || content_type.subtype() == mime_guess::mime::XML
+        || content_type.suffix() == Some(mime_guess::mime::XML);
+}
+
+/**
+ * Builds a Content-Disposition header value. The plain filename is an ascii fallback for old clients, `filename*`
+ * carries the exact name percent-encoded.
+ */
+fn content_disposition(filename: &str, download: bool) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || " ._-()".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"._-".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect();
+
+    return format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        if download { "attachment" } else { "inline" },
+        fallback,
+        encoded
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::SearchResponse;
+    use crate::index::tantivy_backend::TantivyIndex;
+    use crate::index::testing::{document, TempDir};
+    use crate::index::WritableIndex;
+    use argon2::password_hash::rand_core::OsRng;
+    use argon2::password_hash::SaltString;
+    use argon2::{Algorithm, Params, PasswordHasher, Version};
+    use rocket::local::blocking::Client;
+    use std::fs;
+    use std::sync::Once;
+
+    static SET_STATE: Once = Once::new();
+
+    /**
+     * Client for a web server over an in-memory index, shared by every test since the server state is global.
+     */
+    fn client() -> Client {
+        SET_STATE.call_once(|| {
+            let index = TantivyIndex::create_in_memory().unwrap();
+            {
+                let mut writer = index.begin_add_documents().unwrap();
+                for (path, keywords) in [
+                    ("/data/docs/alpha.txt", "quarterly report"),
+                    ("/data/docs/beta.md", "quarterly budget"),
+                    ("/data/media/gamma.txt", "holiday report"),
+                ] {
+                    writer
+                        .add_document(&document(path), &vec![String::from(keywords)])
+                        .unwrap();
+                }
+                writer.commit().unwrap();
+            }
+            let config: Config = serde_yaml::from_str(
+                "
+                database_location: /nonexistent
+                indexed_directories:
+                  - path: /data
+                auth:
+                  tokens:
+                    - name: admin
+                      token: admin-token
+                    - name: scoped
+                      token: scoped-token
+                  access:
+                    - users: [admin]
+                      paths: [/]
+                    - users: [scoped]
+                      paths: [/data/docs]
+                ",
+            )
+            .unwrap();
+            set_state(Arc::new(index), Arc::new(config)).unwrap();
+        });
+        return Client::tracked(build_rocket(RocketConfig::debug_default())).unwrap();
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        return Header::new("Authorization", format!("Bearer {}", token));
+    }
+
+    fn search_paths(client: &Client, token: &str, query: &str) -> Vec<String> {
+        let response = client
+            .get(format!("/api/v1/search?query={}", query))
+            .header(bearer(token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let results: SearchResponse = response.into_json().unwrap();
+        let mut paths: Vec<String> = results
+            .results
+            .iter()
+            .map(|hit| hit.document.metadata.path.to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        return paths;
+    }
+
+    #[test]
+    fn search_returns_hits_within_the_users_scope() {
+        let client = client();
+        assert_eq!(
+            search_paths(&client, "admin-token", "report"),
+            vec!["/data/docs/alpha.txt", "/data/media/gamma.txt"]
+        );
+        assert_eq!(
+            search_paths(&client, "scoped-token", "report"),
+            vec!["/data/docs/alpha.txt"]
+        );
+    }
+
+    #[test]
+    fn stats_require_access_to_the_whole_index() {
+        let client = client();
+        let response = client
+            .get("/api/stats")
+            .header(bearer("admin-token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let stats: IndexStats = response.into_json().unwrap();
+        assert_eq!(stats.total_documents, 3);
+
+        let response = client
+            .get("/api/stats")
+            .header(bearer("scoped-token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let error: ApiError = response.into_json().unwrap();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+    }
+
+    #[test]
+    fn requests_without_valid_credentials_are_rejected() {
+        let client = client();
+        let response = client.get("/api/v1/search?query=report").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response
+            .headers()
+            .get_one("WWW-Authenticate")
+            .unwrap()
+            .starts_with("Basic "));
+        let error: ApiError = response.into_json().unwrap();
+        assert_eq!(error.code, ErrorCode::Unauthorized);
+        assert_eq!(
+            client
+                .get("/api/v1/search?query=report")
+                .header(bearer("wrong-token"))
+                .dispatch()
+                .status(),
+            Status::Unauthorized
+        );
+    }
+
+    /**
+     * Credentials for a token with access to everything and a user restricted by their group, the user's password is
+     * hashed with the cheapest argon2 parameters to keep the tests fast.
+     */
+    fn auth_config(password: &str) -> AuthConfig {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8, 1, 1, None).unwrap(),
+        );
+        let hash = argon2.hash_password(password.as_bytes(), &salt).unwrap();
+        return serde_yaml::from_str(&format!(
+            "
+            users:
+              - username: alice
+                password_hash: {}
+                groups: [staff]
+                uid: 1000
+            tokens:
+              - name: admin
+                token: admin-token
+            access:
+              - users: [admin]
+                paths: [/]
+              - groups: [staff]
+                paths: [/data/docs]
+            ",
+            hash
+        ))
+        .unwrap();
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        return format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        );
+    }
+
+    #[test]
+    fn bearer_token_authenticates_its_client() {
+        let auth = auth_config("secret");
+        let user = authenticate(&auth, "Bearer admin-token").unwrap();
+        assert_eq!(user.name.as_deref(), Some("admin"));
+        assert!(user.scope.paths.is_none());
+
+        assert!(authenticate(&auth, "Bearer wrong-token").is_none());
+        assert!(authenticate(&auth, "Bearer ").is_none());
+    }
+
+    #[test]
+    fn basic_auth_checks_the_users_password() {
+        let auth = auth_config("secret");
+        let user = authenticate(&auth, &basic("alice", "secret")).unwrap();
+        assert_eq!(user.name.as_deref(), Some("alice"));
+        assert_eq!(user.scope.paths, Some(vec![PathBuf::from("/data/docs")]));
+
+        assert!(authenticate(&auth, &basic("alice", "wrong")).is_none());
+        assert!(authenticate(&auth, &basic("mallory", "secret")).is_none());
+        assert!(authenticate(&auth, "Basic not-base64").is_none());
+        assert!(authenticate(&auth, "Digest alice").is_none());
+    }
+
+    #[test]
+    fn verified_credentials_skip_the_password_check() {
+        let auth = auth_config("cached");
+        let header = basic("alice", "cached");
+        assert!(authenticate(&auth, &header).is_some());
+        assert!(verified_credentials
+            .lock()
+            .unwrap()
+            .contains(&credentials_digest(&header)));
+        let wrong = basic("alice", "uncached");
+        assert!(authenticate(&auth, &wrong).is_none());
+        assert!(!verified_credentials
+            .lock()
+            .unwrap()
+            .contains(&credentials_digest(&wrong)));
+
+        // a remembered header is accepted without hashing the password again, even against a hash that can't verify.
+        let mut unverifiable = auth.clone();
+        unverifiable.users[0].password_hash = String::from("invalid");
+        assert!(authenticate(&unverifiable, &header).is_some());
+        assert!(authenticate(&unverifiable, &basic("alice", "other")).is_none());
+    }
+
+    #[test]
+    fn resolves_only_paths_within_an_indexed_directory() {
+        let dir = TempDir::new("resolve-indexed-path");
+        let root = dir.path.join("root");
+        let outside = dir.path.join("outside");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("docs/a.txt"), "a").unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linkdir")).unwrap();
+        std::os::unix::fs::symlink(&root, dir.path.join("alias")).unwrap();
+
+        let resolve = |indexed: &Path, path: PathBuf| {
+            let config: Config = serde_yaml::from_str(&format!(
+                "{{database_location: /nonexistent, indexed_directories: [{{path: {:?}}}]}}",
+                indexed
+            ))
+            .unwrap();
+            return Runtime::new()
+                .unwrap()
+                .block_on(resolve_indexed_path(&config, &path));
+        };
+        let canonical = fs::canonicalize(root.join("docs/a.txt")).unwrap();
+        assert_eq!(
+            resolve(&root, root.join("docs/a.txt")),
+            Some(canonical.clone())
+        );
+        // the indexed directory itself may be a symlink.
+        assert_eq!(
+            resolve(&dir.path.join("alias"), dir.path.join("alias/docs/a.txt")),
+            Some(canonical)
+        );
+
+        assert_eq!(resolve(&root, root.join("docs/../docs/a.txt")), None);
+        assert_eq!(resolve(&root, root.join("../outside/secret.txt")), None);
+        assert_eq!(resolve(&root, PathBuf::from("docs/a.txt")), None);
+        assert_eq!(resolve(&root, root.join("link.txt")), None);
+        assert_eq!(resolve(&root, root.join("linkdir/secret.txt")), None);
+        assert_eq!(resolve(&root, root.join("docs/missing.txt")), None);
+        assert_eq!(resolve(&root, outside.join("secret.txt")), None);
+    }
+
+    #[test]
+    fn parses_satisfiable_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=0-5000", 1000), Some((0, 999)));
+        // suffix ranges count back from the end, longer suffixes cover the whole file.
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+        // open-ended ranges run to the end of the file.
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=999-", 1000), Some((999, 999)));
+
+        // starts beyond the end of the file can't be satisfied.
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=1000-1100", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=50-10", 1000), None);
+        assert_eq!(parse_range("bytes=a-10", 1000), None);
+        assert_eq!(parse_range("items=0-10", 1000), None);
+    }
+
+    #[test]
+    fn files_that_can_run_scripts_are_always_downloaded() {
+        let is_active = |path: &str| {
+            return is_active_content(&mime_guess::from_path(path).first_or_octet_stream());
+        };
+        assert!(is_active("/data/page.html"));
+        assert!(is_active("/data/image.svg"));
+        assert!(is_active("/data/page.xhtml"));
+        assert!(is_active("/data/feed.xml"));
+        assert!(!is_active("/data/notes.txt"));
+        assert!(!is_active("/data/report.pdf"));
+        assert!(!is_active("/data/photo.jpg"));
+        assert!(!is_active("/data/unknown"));
+
+        assert_eq!(
+            content_disposition("report.pdf", false),
+            "inline; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("naïve \"plan\".svg", true),
+            "attachment; filename=\"na_ve _plan_.svg\"; filename*=UTF-8''na%C3%AFve%20%22plan%22.svg"
+        );
     }
 }
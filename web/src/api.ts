@@ -1,5 +1,4 @@
 import axios from "axios";
-import qs from "qs";
 import { env } from "process";
 
 const backend = env.BACKEND_URL || "";
@@ -8,6 +7,7 @@ console.log(`Using backend at ${backend}`);
 interface DocumentMetadata {
   path: string;
   size: number;
+  modified: number;
 }
 
 interface Document {
@@ -17,22 +17,99 @@ interface Document {
   preview_img_path: string;
 }
 
+interface SearchHit {
+  id: string;
+  score: number;
+  document: Document;
+}
+
+interface FacetCount {
+  value: string;
+  count: number;
+}
+
 interface SearchResults {
-  results: Document[];
-  error: string | undefined;
-  latency: number;
+  results: SearchHit[];
+  total_hits: number;
+  directories: FacetCount[];
+  file_types: FacetCount[];
+  latency_ms: number;
+}
+
+export interface ApiError {
+  code:
+    | "invalid_request"
+    | "invalid_query"
+    | "invalid_option"
+    | "unauthorized"
+    | "forbidden"
+    | "indexing_in_progress"
+    | "not_indexing"
+    | "internal_error";
+  message: string;
 }
 
+export type SortField = "relevance" | "modified" | "size" | "name";
+export type SortOrder = "asc" | "desc";
+
 export const search = async (
   query: string,
   limit: number = 100,
-  offset: number = 0
+  offset: number = 0,
+  sort: SortField = "relevance",
+  order: SortOrder = "desc",
+  fuzzy: number | undefined = undefined
 ) => {
-  const results = await axios.get(`${backend}/query`, {
-    params: { query, limit, offset },
+  const results = await axios.get(`${backend}/api/v1/search`, {
+    params: { query, limit, offset, sort, order, fuzzy },
+    // errors carry an ApiError body, return it rather than throwing.
+    validateStatus: () => true,
   });
 
   console.log(results);
 
-  return results.data as any as SearchResults;
+  return results.data as any as SearchResults | ApiError;
+};
+
+// identifies this page's streaming searches, so each new search cancels the previous one on the server.
+const session = Math.random().toString(36).slice(2);
+
+export const streamSearch = (
+  query: string,
+  onResults: (results: SearchResults, complete: boolean) => void,
+  onError: (error: ApiError) => void,
+  limit: number = 100,
+  sort: SortField = "relevance",
+  order: SortOrder = "desc"
+) => {
+  const params = new URLSearchParams({
+    query,
+    limit: limit.toString(),
+    sort,
+    order,
+    session,
+  });
+  const source = new EventSource(`${backend}/api/v1/search/stream?${params}`);
+  source.addEventListener("filenames", (e) => {
+    onResults(JSON.parse((e as MessageEvent).data), false);
+  });
+  source.addEventListener("results", (e) => {
+    onResults(JSON.parse((e as MessageEvent).data), true);
+    source.close();
+  });
+  source.addEventListener("cancelled", () => source.close());
+  source.addEventListener("error", (e) => {
+    // connection failures carry no data, EventSource would otherwise keep reconnecting.
+    const data = (e as MessageEvent).data;
+    if (data) {
+      onError(JSON.parse(data));
+    }
+    source.close();
+  });
+
+  return () => source.close();
+};
+
+export const fileUrl = (id: string, download: boolean = false) => {
+  return `${backend}/file/${id}` + (download ? "?download=true" : "");
 };
@@ -3,22 +3,17 @@ import * as React from "react";
 import _ from "lodash";
 import * as api from "../api";
 
-let timeout: NodeJS.Timeout | undefined;
-
 export const App = () => {
   const [searchTerm, setSearchTerm] = React.useState("");
   const [results, setResults] = React.useState<any>();
 
   React.useEffect(() => {
-    (async () => {
-      if (timeout) {
-        clearTimeout(timeout);
-      }
-      timeout = setTimeout(async () => {
-        const res = await api.search(searchTerm);
-        setResults(() => res);
-      }, 100);
-    })();
+    // filename matches arrive first and are replaced by the full-text results.
+    return api.streamSearch(
+      searchTerm,
+      (res) => setResults(() => res),
+      (error) => setResults(() => error)
+    );
   }, [searchTerm]);
 
   return (
@@ -31,6 +26,14 @@ export const App = () => {
           onChange={(e) => setSearchTerm(e.target.value)}
         ></input>
       </div>
+      <ul>
+        {(results?.results || []).map((hit: any) => (
+          <li key={hit.id}>
+            <a href={api.fileUrl(hit.id)}>{hit.document.metadata.path}</a> (
+            <a href={api.fileUrl(hit.id, true)}>download</a>)
+          </li>
+        ))}
+      </ul>
       <pre>
         <code>{"RESULTS: " + JSON.stringify(results, null, 2)}</code>
       </pre>